@@ -0,0 +1,59 @@
+extern crate spinlock;
+
+use spinlock::{Spinlock, TryLockError};
+
+use std::io::Write;
+
+static NUM_THREADS: usize = 8;
+static NUM_ITERS: usize = 100_000;
+
+// the pre-TTAS acquisition path: every failed attempt is another
+// read-modify-write of the flag
+fn lock_tas(spinlock: &Spinlock<Vec<u8>>) -> spinlock::SpinlockGuard<'_, Vec<u8>> {
+    loop {
+        match spinlock.try_lock() {
+            Ok(guard) => return guard,
+            Err(TryLockError::WouldBlock) => std::hint::spin_loop(),
+            Err(TryLockError::Poisoned(_)) => panic!("poisoned"),
+        }
+    }
+}
+
+fn lock_ttas(spinlock: &Spinlock<Vec<u8>>) -> spinlock::SpinlockGuard<'_, Vec<u8>> {
+    spinlock.lock().expect("poisoned")
+}
+
+fn run(name: &str,
+       lock: fn(&Spinlock<Vec<u8>>) -> spinlock::SpinlockGuard<'_, Vec<u8>>) {
+    let spinlock = std::sync::Arc::new(Spinlock::new(Vec::new()));
+    let start = std::time::Instant::now();
+
+    let threads = (0..NUM_THREADS).map(|_| {
+        let cloned = spinlock.clone();
+
+        std::thread::spawn(move || {
+            for _ in 0..NUM_ITERS {
+                let mut guard = lock(&cloned);
+
+                guard.clear();
+                guard.write_fmt(format_args!("{:?}\n", std::thread::current().id()))
+                    .expect("couldn't write to buffer");
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().expect("couldn't join thread");
+    }
+
+    let elapsed = start.elapsed();
+
+    println!("{}: {} threads x {} iterations in {:?} ({:?}/acquisition)",
+             name, NUM_THREADS, NUM_ITERS, elapsed,
+             elapsed / (NUM_THREADS * NUM_ITERS) as u32);
+}
+
+fn main() {
+    run("test-and-set", lock_tas);
+    run("test-and-test-and-set", lock_ttas);
+}
@@ -0,0 +1,344 @@
+use cache_padded::CachePadded;
+use poison;
+use relax;
+use sync;
+
+// Anderson's queue lock: each of up to N waiters spins on its own slot, and
+// releasing the lock sets the slot of the next waiter in line. once N
+// threads are inside, further arrivals wait for one of them to leave before
+// taking a slot
+pub struct ArrayLock<T: ?Sized, const N: usize, R = relax::Spin> {
+    slots: [CachePadded<sync::atomic::AtomicBool>; N],
+    next_slot: CachePadded<sync::atomic::AtomicUsize>,
+    occupancy: CachePadded<sync::atomic::AtomicUsize>,
+    poison: poison::Flag,
+    relax: core::marker::PhantomData<R>,
+    data: core::cell::UnsafeCell<T>,
+}
+
+impl<T, const N: usize> ArrayLock<T, N> {
+    loom_const_fn! {
+        pub fn new(t: T) -> ArrayLock<T, N> {
+            ArrayLock::with_relax(t)
+        }
+    }
+}
+
+impl<T, const N: usize, R> ArrayLock<T, N, R> {
+    #[cfg(not(loom))]
+    pub const fn with_relax(t: T) -> ArrayLock<T, N, R> {
+        assert!(N > 0, "an ArrayLock needs at least one slot");
+
+        let mut slots = [
+            const { CachePadded::new(sync::atomic::AtomicBool::new(false)) }; N
+        ];
+        slots[0] = CachePadded::new(sync::atomic::AtomicBool::new(true));
+
+        ArrayLock {
+            slots,
+            next_slot: CachePadded::new(sync::atomic::AtomicUsize::new(0)),
+            occupancy: CachePadded::new(sync::atomic::AtomicUsize::new(0)),
+            poison: poison::Flag::new(),
+            relax: core::marker::PhantomData,
+            data: core::cell::UnsafeCell::new(t),
+        }
+    }
+
+    #[cfg(loom)]
+    pub fn with_relax(t: T) -> ArrayLock<T, N, R> {
+        assert!(N > 0, "an ArrayLock needs at least one slot");
+
+        ArrayLock {
+            slots: core::array::from_fn(|i| {
+                CachePadded::new(sync::atomic::AtomicBool::new(i == 0))
+            }),
+            next_slot: CachePadded::new(sync::atomic::AtomicUsize::new(0)),
+            occupancy: CachePadded::new(sync::atomic::AtomicUsize::new(0)),
+            poison: poison::Flag::new(),
+            relax: core::marker::PhantomData,
+            data: core::cell::UnsafeCell::new(t),
+        }
+    }
+}
+
+impl<T: ?Sized, const N: usize, R> ArrayLock<T, N, R> {
+    pub fn lock(&self) -> poison::LockResult<ArrayLockGuard<'_, T, N, R>>
+    where
+        R: relax::Relax,
+    {
+        let slot = unsafe { self.raw_lock() };
+
+        self.poison.result(ArrayLockGuard{
+            lock: self,
+            poison: self.poison.guard(),
+            slot,
+        })
+    }
+
+    pub fn try_lock(&self) -> poison::TryLockResult<ArrayLockGuard<'_, T, N, R>> {
+        let slot = match unsafe { self.raw_try_lock() } {
+            Some(slot) => slot,
+            None => return Err(poison::TryLockError::WouldBlock),
+        };
+
+        Ok(self.poison.result(ArrayLockGuard{
+            lock: self,
+            poison: self.poison.guard(),
+            slot,
+        })?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.poison.info()
+    }
+
+    pub fn into_inner(self) -> poison::LockResult<T> where T: Sized {
+        let ArrayLock { poison, data, .. } = self;
+
+        poison.result(data.into_inner())
+    }
+
+    pub fn get_mut(&mut self) -> poison::LockResult<&mut T> {
+        let data = unsafe { &mut *self.data.get() };
+
+        self.poison.result(data)
+    }
+
+    unsafe fn raw_lock(&self) -> usize where R: relax::Relax {
+        let mut relax = R::default();
+
+        while self.occupancy.fetch_add(1, sync::atomic::Ordering::Relaxed) >= N {
+            self.occupancy.fetch_sub(1, sync::atomic::Ordering::Relaxed);
+
+            while self.occupancy.load(sync::atomic::Ordering::Relaxed) >= N {
+                relax.relax();
+            }
+        }
+
+        let slot = self.take_slot();
+
+        while !self.slots[slot].load(sync::atomic::Ordering::Acquire) {
+            relax.relax();
+        }
+
+        self.slots[slot].store(false, sync::atomic::Ordering::Relaxed);
+
+        slot
+    }
+
+    // only succeeds if nobody holds or is waiting for the lock, in which case
+    // the slot we take has already been handed to us
+    unsafe fn raw_try_lock(&self) -> Option<usize> {
+        if self.occupancy.compare_exchange(
+            0,
+            1,
+            sync::atomic::Ordering::Acquire,
+            sync::atomic::Ordering::Relaxed
+        ).is_err() {
+            return None;
+        }
+
+        let slot = self.take_slot();
+        self.slots[slot].store(false, sync::atomic::Ordering::Relaxed);
+
+        Some(slot)
+    }
+
+    unsafe fn raw_unlock(&self, slot: usize) {
+        self.slots[(slot + 1) % N].store(true, sync::atomic::Ordering::Release);
+        self.occupancy.fetch_sub(1, sync::atomic::Ordering::Release);
+    }
+
+    // wraps at N rather than at usize::MAX, which N needn't divide
+    fn take_slot(&self) -> usize {
+        match self.next_slot.fetch_update(
+            sync::atomic::Ordering::Relaxed,
+            sync::atomic::Ordering::Relaxed,
+            |slot| Some((slot + 1) % N)
+        ) {
+            Ok(slot) | Err(slot) => slot,
+        }
+    }
+}
+
+impl<T: ?Sized, const N: usize, R> core::panic::UnwindSafe
+    for ArrayLock<T, N, R> { }
+
+impl<T: ?Sized, const N: usize, R> core::panic::RefUnwindSafe
+    for ArrayLock<T, N, R> { }
+
+unsafe impl<T: ?Sized + Send, const N: usize, R> Send for ArrayLock<T, N, R> { }
+
+unsafe impl<T: ?Sized + Send, const N: usize, R> Sync for ArrayLock<T, N, R> { }
+
+impl<T, const N: usize, R> From<T> for ArrayLock<T, N, R> {
+    fn from(t: T) -> ArrayLock<T, N, R> {
+        ArrayLock::with_relax(t)
+    }
+}
+
+impl<T: Default, const N: usize, R> Default for ArrayLock<T, N, R> {
+    fn default() -> ArrayLock<T, N, R> {
+        ArrayLock::with_relax(T::default())
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug, const N: usize, R> core::fmt::Debug
+    for ArrayLock<T, N, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.try_lock() {
+            Ok(guard) => f.debug_struct("ArrayLock")
+                .field("data", &&*guard)
+                .finish(),
+            Err(poison::TryLockError::Poisoned(err)) => {
+                f.debug_struct("ArrayLock")
+                    .field("data", &&**err.get_ref())
+                    .finish()
+            },
+            Err(poison::TryLockError::WouldBlock) => {
+                struct LockedPlaceholder;
+
+                impl core::fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self,
+                           f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                f.debug_struct("ArrayLock")
+                    .field("data", &LockedPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+pub struct ArrayLockGuard<'a, T: ?Sized + 'a, const N: usize, R: 'a = relax::Spin> {
+    lock: &'a ArrayLock<T, N, R>,
+    poison: poison::Guard,
+    slot: usize,
+}
+
+unsafe impl<'a, T: ?Sized + Sync, const N: usize, R> Sync
+    for ArrayLockGuard<'a, T, N, R> { }
+
+impl<'a, T: ?Sized, const N: usize, R> core::ops::Deref
+    for ArrayLockGuard<'a, T, N, R>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized, const N: usize, R> core::ops::DerefMut
+    for ArrayLockGuard<'a, T, N, R>
+{
+    fn deref_mut(&mut self) -> &mut T {
+        match unsafe { self.lock.data.get().as_mut() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, const N: usize, R> core::fmt::Debug
+    for ArrayLockGuard<'a, T, N, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("ArrayLockGuard")
+            .field("lock", &self.lock)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, const N: usize, R> core::fmt::Display
+    for ArrayLockGuard<'a, T, N, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, const N: usize, R> Drop for ArrayLockGuard<'a, T, N, R> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+
+        unsafe { self.lock.raw_unlock(self.slot); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use {ArrayLock, TryLockError};
+
+    #[test]
+    fn already_locked() {
+        let lock = ArrayLock::<(), 4>::new(());
+        assert!(!lock.is_poisoned());
+
+        let guard = lock.lock();
+        assert!(guard.is_ok());
+
+        match lock.try_lock() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        drop(guard);
+        assert!(lock.try_lock().is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn poisoned() {
+        let lock = ArrayLock::<(), 4>::new(());
+        assert!(!lock.is_poisoned());
+
+        let result = std::panic::catch_unwind(|| {
+            if let Ok(_guard) = lock.lock() {
+                panic!();
+            }
+        });
+
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+        assert!(lock.into_inner().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn more_threads_than_slots() {
+        static NUM_THREADS: usize = 8;
+        static NUM_ITERS: usize = 1000;
+
+        static LOCK: ArrayLock<usize, 2, ::relax::SpinThenYield> =
+            ArrayLock::with_relax(0);
+
+        let threads: std::vec::Vec<_> = (0..NUM_THREADS).map(|_| {
+            std::thread::spawn(move || {
+                for _ in 0..NUM_ITERS {
+                    *LOCK.lock().unwrap() += 1;
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(*LOCK.lock().unwrap(), NUM_THREADS * NUM_ITERS);
+    }
+}
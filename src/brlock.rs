@@ -0,0 +1,497 @@
+use cache_padded::CachePadded;
+use poison;
+use policy;
+use relax;
+use sync;
+use Spinlock;
+
+// a big-reader lock: N spinlocks, each on its own cache line. a reader locks
+// only the slot its thread maps to, so readers on different slots never
+// touch the same memory; a writer locks every slot in order. reads scale
+// with the number of slots, writes get slower with it.
+//
+// reads only scale while there are no more reading threads than slots.
+// past that, threads share slots, and readers that share a slot exclude one
+// another, so reads on a shared slot take turns. for the same reason a
+// thread must not hold two reads of the same lock at once; trying to panics
+// rather than waiting on itself forever.
+//
+// poisoning follows SpinRwLock: only a panicking writer poisons the lock
+pub struct BrLock<T: ?Sized, const N: usize, R = relax::Spin> {
+    slots: [CachePadded<Slot<R>>; N],
+    poison: poison::Flag,
+    data: core::cell::UnsafeCell<T>,
+}
+
+struct Slot<R> {
+    lock: Spinlock<(), R, policy::NoPoison>,
+    // `reader()` of the thread reading through this slot, or zero
+    reader: sync::atomic::AtomicUsize,
+}
+
+impl<R> Slot<R> {
+    loom_const_fn! {
+        pub fn new() -> Slot<R> {
+            Slot {
+                lock: Spinlock::with_relax(()),
+                reader: sync::atomic::AtomicUsize::new(0),
+            }
+        }
+    }
+}
+
+// threads are numbered from one the first time they read, and take slots
+// round-robin by that number, using the same slot of every BrLock after that
+static NEXT_READER: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(1);
+
+thread_local! {
+    static READER: usize =
+        NEXT_READER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+}
+
+// zero once the thread's locals have been destroyed
+fn reader() -> usize {
+    READER.try_with(|reader| *reader).unwrap_or(0)
+}
+
+impl<T, const N: usize> BrLock<T, N> {
+    loom_const_fn! {
+        pub fn new(t: T) -> BrLock<T, N> {
+            BrLock::with_relax(t)
+        }
+    }
+}
+
+impl<T, const N: usize, R> BrLock<T, N, R> {
+    #[cfg(not(loom))]
+    pub const fn with_relax(t: T) -> BrLock<T, N, R> {
+        assert!(N > 0, "a BrLock needs at least one slot");
+
+        BrLock {
+            slots: [const { CachePadded::new(Slot::new()) }; N],
+            poison: poison::Flag::new(),
+            data: core::cell::UnsafeCell::new(t),
+        }
+    }
+
+    #[cfg(loom)]
+    pub fn with_relax(t: T) -> BrLock<T, N, R> {
+        assert!(N > 0, "a BrLock needs at least one slot");
+
+        BrLock {
+            slots: core::array::from_fn(|_| CachePadded::new(Slot::new())),
+            poison: poison::Flag::new(),
+            data: core::cell::UnsafeCell::new(t),
+        }
+    }
+}
+
+impl<T: ?Sized, const N: usize, R> BrLock<T, N, R> {
+    pub fn read(&self) -> poison::LockResult<BrLockReadGuard<'_, T, N, R>>
+    where
+        R: relax::Relax,
+    {
+        let reader = reader();
+        let slot = reader % N;
+
+        unsafe { self.raw_read(slot, reader); }
+
+        self.poison.result(BrLockReadGuard{ lock: self, slot })
+    }
+
+    pub fn try_read(&self) -> poison::TryLockResult<BrLockReadGuard<'_, T, N, R>> {
+        let reader = reader();
+        let slot = reader % N;
+
+        if unsafe { !self.raw_try_read(slot, reader) } {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(BrLockReadGuard{ lock: self, slot })?)
+    }
+
+    pub fn write(&self) -> poison::LockResult<BrLockWriteGuard<'_, T, N, R>>
+    where
+        R: relax::Relax,
+    {
+        unsafe { self.raw_write(); }
+
+        self.poison.result(BrLockWriteGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })
+    }
+
+    pub fn try_write(&self) -> poison::TryLockResult<BrLockWriteGuard<'_, T, N, R>> {
+        if unsafe { !self.raw_try_write() } {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(BrLockWriteGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.poison.info()
+    }
+
+    pub fn into_inner(self) -> poison::LockResult<T> where T: Sized {
+        let BrLock { poison, data, .. } = self;
+
+        poison.result(data.into_inner())
+    }
+
+    pub fn get_mut(&mut self) -> poison::LockResult<&mut T> {
+        let data = unsafe { &mut *self.data.get() };
+
+        self.poison.result(data)
+    }
+
+    // only the thread reading through a slot stores its number there, so
+    // finding our own means we already hold the slot
+    unsafe fn raw_read(&self, slot: usize, reader: usize)
+    where
+        R: relax::Relax,
+    {
+        let slot = &self.slots[slot];
+
+        if reader != 0
+            && slot.reader.load(sync::atomic::Ordering::Relaxed) == reader
+        {
+            panic!("BrLock read again by a thread that already holds a read");
+        }
+
+        slot.lock.raw_lock();
+        slot.reader.store(reader, sync::atomic::Ordering::Relaxed);
+    }
+
+    unsafe fn raw_try_read(&self, slot: usize, reader: usize) -> bool {
+        let slot = &self.slots[slot];
+
+        if !slot.lock.raw_try_lock() {
+            return false;
+        }
+
+        slot.reader.store(reader, sync::atomic::Ordering::Relaxed);
+
+        true
+    }
+
+    unsafe fn raw_read_unlock(&self, slot: usize) {
+        let slot = &self.slots[slot];
+
+        slot.reader.store(0, sync::atomic::Ordering::Relaxed);
+        slot.lock.raw_unlock();
+    }
+
+    // every writer takes the slots in the same order, so two writers can't
+    // each hold a slot the other is waiting for
+    unsafe fn raw_write(&self) where R: relax::Relax {
+        for slot in self.slots.iter() {
+            slot.lock.raw_lock();
+        }
+    }
+
+    unsafe fn raw_try_write(&self) -> bool {
+        for (i, slot) in self.slots.iter().enumerate() {
+            if !slot.lock.raw_try_lock() {
+                for taken in self.slots[..i].iter() {
+                    taken.lock.raw_unlock();
+                }
+
+                return false;
+            }
+        }
+
+        true
+    }
+
+    unsafe fn raw_write_unlock(&self) {
+        for slot in self.slots.iter() {
+            slot.lock.raw_unlock();
+        }
+    }
+}
+
+impl<T: ?Sized, const N: usize, R> core::panic::UnwindSafe for BrLock<T, N, R> { }
+
+impl<T: ?Sized, const N: usize, R> core::panic::RefUnwindSafe
+    for BrLock<T, N, R> { }
+
+unsafe impl<T: ?Sized + Send, const N: usize, R> Send for BrLock<T, N, R> { }
+
+unsafe impl<T: ?Sized + Send + Sync, const N: usize, R> Sync
+    for BrLock<T, N, R> { }
+
+impl<T, const N: usize, R> From<T> for BrLock<T, N, R> {
+    fn from(t: T) -> BrLock<T, N, R> {
+        BrLock::with_relax(t)
+    }
+}
+
+impl<T: Default, const N: usize, R> Default for BrLock<T, N, R> {
+    fn default() -> BrLock<T, N, R> {
+        BrLock::with_relax(T::default())
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug, const N: usize, R> core::fmt::Debug
+    for BrLock<T, N, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.try_read() {
+            Ok(guard) => f.debug_struct("BrLock")
+                .field("data", &&*guard)
+                .finish(),
+            Err(poison::TryLockError::Poisoned(err)) => {
+                f.debug_struct("BrLock")
+                    .field("data", &&**err.get_ref())
+                    .finish()
+            },
+            Err(poison::TryLockError::WouldBlock) => {
+                struct LockedPlaceholder;
+
+                impl core::fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self,
+                           f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                f.debug_struct("BrLock")
+                    .field("data", &LockedPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+pub struct BrLockReadGuard<'a, T: ?Sized + 'a, const N: usize, R: 'a = relax::Spin> {
+    lock: &'a BrLock<T, N, R>,
+    slot: usize,
+}
+
+unsafe impl<'a, T: ?Sized + Sync, const N: usize, R> Sync
+    for BrLockReadGuard<'a, T, N, R> { }
+
+impl<'a, T: ?Sized, const N: usize, R> core::ops::Deref
+    for BrLockReadGuard<'a, T, N, R>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, const N: usize, R> core::fmt::Debug
+    for BrLockReadGuard<'a, T, N, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("BrLockReadGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, const N: usize, R> core::fmt::Display
+    for BrLockReadGuard<'a, T, N, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, const N: usize, R> Drop for BrLockReadGuard<'a, T, N, R> {
+    fn drop(&mut self) {
+        unsafe { self.lock.raw_read_unlock(self.slot); }
+    }
+}
+
+pub struct BrLockWriteGuard<'a, T: ?Sized + 'a, const N: usize, R: 'a = relax::Spin> {
+    lock: &'a BrLock<T, N, R>,
+    poison: poison::Guard,
+}
+
+unsafe impl<'a, T: ?Sized + Sync, const N: usize, R> Sync
+    for BrLockWriteGuard<'a, T, N, R> { }
+
+impl<'a, T: ?Sized, const N: usize, R> core::ops::Deref
+    for BrLockWriteGuard<'a, T, N, R>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized, const N: usize, R> core::ops::DerefMut
+    for BrLockWriteGuard<'a, T, N, R>
+{
+    fn deref_mut(&mut self) -> &mut T {
+        match unsafe { self.lock.data.get().as_mut() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, const N: usize, R> core::fmt::Debug
+    for BrLockWriteGuard<'a, T, N, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("BrLockWriteGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, const N: usize, R> core::fmt::Display
+    for BrLockWriteGuard<'a, T, N, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, const N: usize, R> Drop for BrLockWriteGuard<'a, T, N, R> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+
+        unsafe { self.lock.raw_write_unlock(); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {relax, BrLock, TryLockError};
+
+    #[test]
+    fn readers_and_writers_exclude() {
+        let lock = BrLock::<_, 4>::new(1);
+
+        let reader = lock.read().unwrap();
+        assert_eq!(*reader, 1);
+
+        match lock.try_write() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        drop(reader);
+
+        let mut writer = lock.try_write().unwrap();
+        *writer += 1;
+
+        match lock.try_read() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        drop(writer);
+
+        assert_eq!(*lock.try_read().unwrap(), 2);
+    }
+
+    #[test]
+    fn poisoned_by_writers_only() {
+        let lock = BrLock::<_, 4>::new(());
+
+        let result = std::panic::catch_unwind(|| {
+            let _guard = lock.read().unwrap();
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert!(!lock.is_poisoned());
+
+        let result = std::panic::catch_unwind(|| {
+            let _guard = lock.write().unwrap();
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+        assert!(lock.read().is_err());
+    }
+
+    #[test]
+    fn more_threads_than_slots() {
+        let lock = std::sync::Arc::new(BrLock::<_, 1>::new(()));
+        let reader = lock.read().unwrap();
+
+        // every thread reads through the one slot, so reads take turns
+        let cloned = lock.clone();
+        let thread = std::thread::spawn(move || match cloned.try_read() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        });
+
+        thread.join().unwrap();
+        drop(reader);
+
+        let cloned = lock.clone();
+        std::thread::spawn(move || drop(cloned.read().unwrap()))
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn reading_twice_panics() {
+        let lock = BrLock::<_, 4>::new(());
+
+        let result = std::panic::catch_unwind(|| {
+            let _first = lock.read().unwrap();
+            let _second = lock.read();
+        });
+
+        assert!(result.is_err());
+        assert!(lock.try_write().is_ok());
+    }
+
+    // twice as many threads as slots, so every slot is shared
+    #[test]
+    fn readers_on_every_slot() {
+        static NUM_THREADS: usize = 8;
+        static NUM_ITERS: usize = 1000;
+
+        let lock = std::sync::Arc::new(
+            BrLock::<_, 4, relax::SpinThenYield>::with_relax(0)
+        );
+
+        let threads: std::vec::Vec<_> = (0..NUM_THREADS).map(|_| {
+            let lock = lock.clone();
+
+            std::thread::spawn(move || {
+                for i in 0..NUM_ITERS {
+                    if i % 10 == 0 {
+                        *lock.write().unwrap() += 1;
+                    } else {
+                        assert!(*lock.read().unwrap() <= NUM_THREADS * NUM_ITERS);
+                    }
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(*lock.read().unwrap(), NUM_THREADS * NUM_ITERS / 10);
+    }
+}
@@ -0,0 +1,76 @@
+use poison;
+use sync;
+
+// lets one thread tell others to stop waiting for a lock, e.g. on shutdown.
+// once cancelled, a token stays cancelled
+pub struct CancelToken {
+    cancelled: sync::atomic::AtomicBool,
+}
+
+impl CancelToken {
+    loom_const_fn! {
+        pub fn new() -> CancelToken {
+            CancelToken { cancelled: sync::atomic::AtomicBool::new(false) }
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, sync::atomic::Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(sync::atomic::Ordering::Acquire)
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> CancelToken {
+        CancelToken::new()
+    }
+}
+
+impl core::fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+pub type CancelLockResult<G> = Result<G, CancelLockError<G>>;
+
+pub enum CancelLockError<T> {
+    Poisoned(poison::PoisonError<T>),
+    Cancelled,
+}
+
+impl<T> From<poison::PoisonError<T>> for CancelLockError<T> {
+    fn from(err: poison::PoisonError<T>) -> CancelLockError<T> {
+        CancelLockError::Poisoned(err)
+    }
+}
+
+impl<T> core::fmt::Debug for CancelLockError<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            CancelLockError::Poisoned(..) => f.write_str("Poisoned(..)"),
+            CancelLockError::Cancelled => f.write_str("Cancelled"),
+        }
+    }
+}
+
+impl<T> core::fmt::Display for CancelLockError<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            CancelLockError::Poisoned(..) => {
+                f.write_str("poisoned lock: another task failed inside")
+            },
+            CancelLockError::Cancelled => {
+                f.write_str("lock acquisition was cancelled")
+            },
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> std::error::Error for CancelLockError<T> { }
@@ -0,0 +1,418 @@
+use poison;
+use relax;
+use sync;
+
+// every waiter spins on the node of the waiter queued before it. acquiring
+// takes one swap of `tail`; releasing hands our node to our successor and
+// keeps our predecessor's node for the next acquisition
+pub struct ClhLock<T: ?Sized, R = relax::Spin> {
+    // a `*mut ClhNode`, tagged with `IDLE` when its owner released the lock
+    // before anyone queued behind it. starts out as a null `IDLE` tail, so
+    // that a new lock needn't allocate
+    tail: sync::atomic::AtomicUsize,
+    poison: poison::Flag,
+    relax: core::marker::PhantomData<R>,
+    data: core::cell::UnsafeCell<T>,
+}
+
+const IDLE: usize = 1;
+
+#[repr(align(64))]
+struct ClhNode {
+    locked: sync::atomic::AtomicBool,
+}
+
+impl ClhNode {
+    fn new() -> ClhNode {
+        ClhNode { locked: sync::atomic::AtomicBool::new(false) }
+    }
+}
+
+#[cfg(not(loom))]
+thread_local! {
+    static SPARE_NODE: core::cell::Cell<Option<Box<ClhNode>>> =
+        const { core::cell::Cell::new(None) };
+}
+
+#[cfg(not(loom))]
+fn take_node() -> *mut ClhNode {
+    let node = SPARE_NODE.try_with(core::cell::Cell::take)
+        .ok()
+        .and_then(|node| node)
+        .unwrap_or_else(|| Box::new(ClhNode::new()));
+
+    Box::into_raw(node)
+}
+
+#[cfg(loom)]
+fn take_node() -> *mut ClhNode {
+    Box::into_raw(Box::new(ClhNode::new()))
+}
+
+// the first owner of a lock has no predecessor and gets back a null node
+#[cfg(not(loom))]
+unsafe fn give_node(node: *mut ClhNode) {
+    if node.is_null() {
+        return;
+    }
+
+    let node = Box::from_raw(node);
+    let _ = SPARE_NODE.try_with(|spare| spare.set(Some(node)));
+}
+
+#[cfg(loom)]
+unsafe fn give_node(node: *mut ClhNode) {
+    if !node.is_null() {
+        drop(Box::from_raw(node));
+    }
+}
+
+impl<T> ClhLock<T> {
+    loom_const_fn! {
+        pub fn new(t: T) -> ClhLock<T> {
+            ClhLock::with_relax(t)
+        }
+    }
+}
+
+impl<T, R> ClhLock<T, R> {
+    loom_const_fn! {
+        pub fn with_relax(t: T) -> ClhLock<T, R> {
+            ClhLock {
+                tail: sync::atomic::AtomicUsize::new(IDLE),
+                poison: poison::Flag::new(),
+                relax: core::marker::PhantomData,
+                data: core::cell::UnsafeCell::new(t),
+            }
+        }
+    }
+}
+
+impl<T: ?Sized, R> ClhLock<T, R> {
+    pub fn lock(&self) -> poison::LockResult<ClhLockGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        let node = take_node();
+        let pred = unsafe { self.raw_lock(node) };
+
+        self.poison.result(ClhLockGuard{
+            lock: self,
+            poison: self.poison.guard(),
+            node,
+            pred,
+        })
+    }
+
+    pub fn try_lock(&self) -> poison::TryLockResult<ClhLockGuard<'_, T, R>> {
+        let node = take_node();
+
+        let pred = match unsafe { self.raw_try_lock(node) } {
+            Some(pred) => pred,
+            None => {
+                unsafe { give_node(node); }
+
+                return Err(poison::TryLockError::WouldBlock);
+            }
+        };
+
+        Ok(self.poison.result(ClhLockGuard{
+            lock: self,
+            poison: self.poison.guard(),
+            node,
+            pred,
+        })?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.poison.info()
+    }
+
+    pub fn into_inner(self) -> poison::LockResult<T> where T: Sized {
+        unsafe {
+            let (tail, poison, data) = {
+                let ClhLock {
+                    ref tail,
+                    ref poison,
+                    ref data,
+                    ..
+                } = self;
+
+                (
+                    core::ptr::read(tail),
+                    core::ptr::read(poison),
+                    core::ptr::read(data),
+                )
+            };
+
+            core::mem::forget(self);
+            give_node(Self::untag(tail.load(sync::atomic::Ordering::Relaxed)));
+
+            poison.result(data.into_inner())
+        }
+    }
+
+    pub fn get_mut(&mut self) -> poison::LockResult<&mut T> {
+        let data = unsafe { &mut *self.data.get() };
+
+        self.poison.result(data)
+    }
+
+    fn untag(tail: usize) -> *mut ClhNode {
+        (tail & !IDLE) as *mut ClhNode
+    }
+
+    // the initial null tail is always tagged `IDLE`, so we never wait on a
+    // null predecessor; we just hand it back for give_node to skip
+    unsafe fn raw_lock(&self, node: *mut ClhNode) -> *mut ClhNode
+    where
+        R: relax::Relax,
+    {
+        (*node).locked.store(true, sync::atomic::Ordering::Relaxed);
+
+        let pred = self.tail.swap(node as usize, sync::atomic::Ordering::AcqRel);
+
+        if pred & IDLE == 0 {
+            let mut relax = R::default();
+
+            while (*Self::untag(pred)).locked.load(sync::atomic::Ordering::Acquire) {
+                relax.relax();
+            }
+        }
+
+        Self::untag(pred)
+    }
+
+    // never dereferences `tail`, which may be recycled by its successor as
+    // soon as we've loaded it
+    unsafe fn raw_try_lock(&self, node: *mut ClhNode) -> Option<*mut ClhNode> {
+        let pred = self.tail.load(sync::atomic::Ordering::Relaxed);
+
+        if pred & IDLE == 0 {
+            return None;
+        }
+
+        (*node).locked.store(true, sync::atomic::Ordering::Relaxed);
+
+        match self.tail.compare_exchange(
+            pred,
+            node as usize,
+            sync::atomic::Ordering::AcqRel,
+            sync::atomic::Ordering::Relaxed
+        ) {
+            Ok(_) => Some(Self::untag(pred)),
+            Err(_) => None,
+        }
+    }
+
+    unsafe fn raw_unlock(&self, node: *mut ClhNode) {
+        if self.tail.compare_exchange(
+            node as usize,
+            node as usize | IDLE,
+            sync::atomic::Ordering::Release,
+            sync::atomic::Ordering::Relaxed
+        ).is_err() {
+            (*node).locked.store(false, sync::atomic::Ordering::Release);
+        }
+    }
+}
+
+impl<T: ?Sized, R> Drop for ClhLock<T, R> {
+    fn drop(&mut self) {
+        let tail = Self::untag(self.tail.load(sync::atomic::Ordering::Relaxed));
+
+        unsafe { give_node(tail); }
+    }
+}
+
+impl<T: ?Sized, R> core::panic::UnwindSafe for ClhLock<T, R> { }
+
+impl<T: ?Sized, R> core::panic::RefUnwindSafe for ClhLock<T, R> { }
+
+unsafe impl<T: ?Sized + Send, R> Send for ClhLock<T, R> { }
+
+unsafe impl<T: ?Sized + Send, R> Sync for ClhLock<T, R> { }
+
+impl<T, R> From<T> for ClhLock<T, R> {
+    fn from(t: T) -> ClhLock<T, R> {
+        ClhLock::with_relax(t)
+    }
+}
+
+impl<T: Default, R> Default for ClhLock<T, R> {
+    fn default() -> ClhLock<T, R> {
+        ClhLock::with_relax(T::default())
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug, R> core::fmt::Debug for ClhLock<T, R> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.try_lock() {
+            Ok(guard) => f.debug_struct("ClhLock")
+                .field("data", &&*guard)
+                .finish(),
+            Err(poison::TryLockError::Poisoned(err)) => {
+                f.debug_struct("ClhLock")
+                    .field("data", &&**err.get_ref())
+                    .finish()
+            },
+            Err(poison::TryLockError::WouldBlock) => {
+                struct LockedPlaceholder;
+
+                impl core::fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self,
+                           f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                f.debug_struct("ClhLock")
+                    .field("data", &LockedPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+pub struct ClhLockGuard<'a, T: ?Sized + 'a, R: 'a = relax::Spin> {
+    lock: &'a ClhLock<T, R>,
+    poison: poison::Guard,
+    node: *mut ClhNode,
+    pred: *mut ClhNode,
+}
+
+unsafe impl<'a, T: ?Sized + Sync, R> Sync for ClhLockGuard<'a, T, R> { }
+
+impl<'a, T: ?Sized, R> core::ops::Deref for ClhLockGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized, R> core::ops::DerefMut for ClhLockGuard<'a, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        match unsafe { self.lock.data.get().as_mut() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for ClhLockGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("ClhLockGuard")
+            .field("lock", &self.lock)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, R> core::fmt::Display
+    for ClhLockGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, R> Drop for ClhLockGuard<'a, T, R> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+
+        unsafe {
+            self.lock.raw_unlock(self.node);
+            give_node(self.pred);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {relax, ClhLock, TryLockError};
+
+    #[test]
+    fn already_locked() {
+        let lock = ClhLock::new(());
+        assert!(!lock.is_poisoned());
+
+        let guard = lock.lock();
+        assert!(guard.is_ok());
+
+        match lock.try_lock() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        drop(guard);
+        assert!(lock.try_lock().is_ok());
+    }
+
+    #[test]
+    fn poisoned() {
+        let lock = ClhLock::new(());
+        assert!(!lock.is_poisoned());
+
+        let result = std::panic::catch_unwind(|| {
+            if let Ok(_guard) = lock.lock() {
+                panic!();
+            }
+        });
+
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+        assert!(lock.into_inner().is_err());
+    }
+
+    #[test]
+    fn usable_in_statics() {
+        static LOCK: ClhLock<usize> = ClhLock::new(0);
+
+        *LOCK.lock().unwrap() += 1;
+        *LOCK.try_lock().unwrap() += 1;
+        assert_eq!(*LOCK.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn served_in_queue_order() {
+        static NUM_THREADS: usize = 8;
+
+        let lock = std::sync::Arc::new(
+            ClhLock::<_, relax::SpinThenYield>::with_relax(Vec::new())
+        );
+        let guard = lock.lock().unwrap();
+
+        let threads: Vec<_> = (0..NUM_THREADS).map(|i| {
+            let tail = lock.tail.load(::sync::atomic::Ordering::Relaxed);
+            let cloned = lock.clone();
+            let thread = std::thread::spawn(move || {
+                cloned.lock().unwrap().push(i);
+            });
+
+            // wait for this thread to enqueue before starting the next one
+            while lock.tail.load(::sync::atomic::Ordering::Relaxed) == tail {
+                std::thread::yield_now();
+            }
+
+            thread
+        }).collect();
+
+        drop(guard);
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(*lock.lock().unwrap(), (0..NUM_THREADS).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,68 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+extern crate core;
+
+#[cfg(loom)]
+extern crate loom;
+
+#[macro_use]
+mod macros;
+
+mod arraylock;
+#[cfg(feature = "std")]
+mod brlock;
+mod cache_padded;
+mod cancel;
+#[cfg(feature = "std")]
+mod clhlock;
+#[cfg(feature = "std")]
+mod mcslock;
+mod once;
+mod phasefair;
+mod poison;
+pub mod policy;
+#[cfg(feature = "std")]
+mod reentrant;
+pub mod relax;
+mod rwlock;
+mod seqlock;
+mod spinlock;
+mod stampedlock;
+mod sync;
+mod ticketlock;
+
+pub use arraylock::{ArrayLock, ArrayLockGuard};
+#[cfg(feature = "std")]
+pub use brlock::{BrLock, BrLockReadGuard, BrLockWriteGuard};
+pub use cancel::{CancelLockError, CancelLockResult, CancelToken};
+#[cfg(feature = "std")]
+pub use clhlock::{ClhLock, ClhLockGuard};
+#[cfg(feature = "std")]
+pub use mcslock::{McsLock, McsLockGuard};
+pub use once::{SpinLazy, SpinOnce, SpinOnceCell};
+pub use phasefair::{
+    PhaseFairRwLock, PhaseFairRwLockReadGuard, PhaseFairRwLockWriteGuard,
+};
+pub use policy::Policy;
+pub use poison::{LockResult, PoisonError, TryLockError, TryLockResult};
+#[cfg(feature = "poison-info")]
+pub use poison::{
+    LockResultWithInfo, PoisonErrorWithInfo, PoisonInfo, TryLockErrorWithInfo,
+    TryLockResultWithInfo
+};
+#[cfg(feature = "std")]
+pub use reentrant::{ReentrantSpinlock, ReentrantSpinlockGuard};
+pub use relax::Relax;
+pub use rwlock::{
+    SpinRwLock, SpinRwLockReadGuard, SpinRwLockUpgradableGuard,
+    SpinRwLockWriteGuard,
+};
+pub use seqlock::{SeqLock, SeqLockWriteGuard};
+pub use spinlock::{AtomicFlag, Spinlock, SpinlockGuard};
+#[cfg(not(loom))]
+pub use spinlock::SPINLOCK_INIT;
+pub use stampedlock::{
+    Stamp, StampedLock, StampedLockReadGuard, StampedLockWriteGuard,
+};
+pub use ticketlock::{TicketLock, TicketLockGuard};
@@ -0,0 +1,13 @@
+// loom's atomics can't be built in a const context, so constructors that
+// create them are only `const` outside of loom models
+macro_rules! loom_const_fn {
+    ($(#[$attr:meta])* pub fn $($rest:tt)*) => {
+        #[cfg(not(loom))]
+        $(#[$attr])*
+        pub const fn $($rest)*
+
+        #[cfg(loom)]
+        $(#[$attr])*
+        pub fn $($rest)*
+    };
+}
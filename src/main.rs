@@ -1,14 +1,14 @@
+extern crate spinlock;
+
 use spinlock::Spinlock;
 
 use std::io::Write;
 use std::os::unix::io::FromRawFd;
 
-mod spinlock;
-
 fn print(spinlock: std::sync::Arc<Spinlock<std::fs::File>>) {
     loop {
-        spinlock.lock()
-            .expect("poisoned")
+        // a thread that panicked mid-write leaves at worst a partial line
+        spinlock.lock_or_recover(|_| ())
             .write_fmt(format_args!("{:?}\n", std::thread::current().id()))
             .expect("couldn't write to stdout");
     }
@@ -20,11 +20,11 @@ fn main() {
     let stdout = unsafe { std::fs::File::from_raw_fd(1) };
     let lock = std::sync::Arc::new(Spinlock::new(stdout));
 
-    let threads = (0..NUM_THREADS).into_iter().map(|_| {
+    let threads = (0..NUM_THREADS).map(|_| {
         let cloned = lock.clone();
 
         std::thread::spawn(move || print(cloned))
-    });
+    }).collect::<Vec<_>>();
 
     println!("spawned {} threads", NUM_THREADS);
 
@@ -0,0 +1,386 @@
+use poison;
+use relax;
+use sync;
+
+// every waiter spins on the `locked` flag of its own node instead of on the
+// lock itself, so a release only touches the cache line of the next waiter
+pub struct McsLock<T: ?Sized, R = relax::Spin> {
+    tail: sync::atomic::AtomicPtr<McsNode>,
+    poison: poison::Flag,
+    relax: core::marker::PhantomData<R>,
+    data: core::cell::UnsafeCell<T>,
+}
+
+#[repr(align(64))]
+struct McsNode {
+    next: sync::atomic::AtomicPtr<McsNode>,
+    locked: sync::atomic::AtomicBool,
+}
+
+impl McsNode {
+    fn new() -> McsNode {
+        McsNode {
+            next: sync::atomic::AtomicPtr::new(core::ptr::null_mut()),
+            locked: sync::atomic::AtomicBool::new(false),
+        }
+    }
+}
+
+// each thread keeps the node from its last acquisition around, so the
+// uncontended path doesn't allocate
+#[cfg(not(loom))]
+thread_local! {
+    static SPARE_NODE: core::cell::Cell<Option<Box<McsNode>>> =
+        const { core::cell::Cell::new(None) };
+}
+
+#[cfg(not(loom))]
+fn take_node() -> Box<McsNode> {
+    SPARE_NODE.try_with(core::cell::Cell::take)
+        .ok()
+        .and_then(|node| node)
+        .unwrap_or_else(|| Box::new(McsNode::new()))
+}
+
+#[cfg(loom)]
+fn take_node() -> Box<McsNode> {
+    Box::new(McsNode::new())
+}
+
+#[cfg(not(loom))]
+fn give_node(node: Box<McsNode>) {
+    let _ = SPARE_NODE.try_with(|spare| spare.set(Some(node)));
+}
+
+#[cfg(loom)]
+fn give_node(_: Box<McsNode>) { }
+
+impl<T> McsLock<T> {
+    loom_const_fn! {
+        pub fn new(t: T) -> McsLock<T> {
+            McsLock::with_relax(t)
+        }
+    }
+}
+
+impl<T, R> McsLock<T, R> {
+    loom_const_fn! {
+        pub fn with_relax(t: T) -> McsLock<T, R> {
+            McsLock {
+                tail: sync::atomic::AtomicPtr::new(core::ptr::null_mut()),
+                poison: poison::Flag::new(),
+                relax: core::marker::PhantomData,
+                data: core::cell::UnsafeCell::new(t),
+            }
+        }
+    }
+}
+
+impl<T: ?Sized, R> McsLock<T, R> {
+    pub fn lock(&self) -> poison::LockResult<McsLockGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        let node = take_node();
+
+        unsafe { self.raw_lock(&node); }
+
+        self.poison.result(McsLockGuard{
+            lock: self,
+            poison: self.poison.guard(),
+            node: Some(node),
+        })
+    }
+
+    pub fn try_lock(&self) -> poison::TryLockResult<McsLockGuard<'_, T, R>> {
+        let node = take_node();
+
+        if unsafe { !self.raw_try_lock(&node) } {
+            give_node(node);
+
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(McsLockGuard{
+            lock: self,
+            poison: self.poison.guard(),
+            node: Some(node),
+        })?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.poison.info()
+    }
+
+    pub fn into_inner(self) -> poison::LockResult<T> where T: Sized {
+        let McsLock { poison, data, .. } = self;
+
+        poison.result(data.into_inner())
+    }
+
+    pub fn get_mut(&mut self) -> poison::LockResult<&mut T> {
+        let data = unsafe { &mut *self.data.get() };
+
+        self.poison.result(data)
+    }
+
+    unsafe fn raw_lock(&self, node: &McsNode) where R: relax::Relax {
+        let node_ptr = node as *const McsNode as *mut McsNode;
+
+        node.next.store(core::ptr::null_mut(), sync::atomic::Ordering::Relaxed);
+        node.locked.store(true, sync::atomic::Ordering::Relaxed);
+
+        let prev = self.tail.swap(node_ptr, sync::atomic::Ordering::AcqRel);
+
+        if prev.is_null() {
+            return;
+        }
+
+        (*prev).next.store(node_ptr, sync::atomic::Ordering::Release);
+
+        let mut relax = R::default();
+
+        while node.locked.load(sync::atomic::Ordering::Acquire) {
+            relax.relax();
+        }
+    }
+
+    unsafe fn raw_try_lock(&self, node: &McsNode) -> bool {
+        let node_ptr = node as *const McsNode as *mut McsNode;
+
+        node.next.store(core::ptr::null_mut(), sync::atomic::Ordering::Relaxed);
+
+        self.tail.compare_exchange(
+            core::ptr::null_mut(),
+            node_ptr,
+            sync::atomic::Ordering::Acquire,
+            sync::atomic::Ordering::Relaxed
+        ).is_ok()
+    }
+
+    unsafe fn raw_unlock(&self, node: &McsNode) {
+        let node_ptr = node as *const McsNode as *mut McsNode;
+        let mut next = node.next.load(sync::atomic::Ordering::Acquire);
+
+        if next.is_null() {
+            if self.tail.compare_exchange(
+                node_ptr,
+                core::ptr::null_mut(),
+                sync::atomic::Ordering::Release,
+                sync::atomic::Ordering::Relaxed
+            ).is_ok() {
+                return;
+            }
+
+            // someone swapped themselves in as the tail but hasn't linked
+            // themselves behind us yet
+            loop {
+                next = node.next.load(sync::atomic::Ordering::Acquire);
+
+                if !next.is_null() {
+                    break;
+                }
+
+                sync::spin_loop();
+            }
+        }
+
+        (*next).locked.store(false, sync::atomic::Ordering::Release);
+    }
+}
+
+impl<T: ?Sized, R> core::panic::UnwindSafe for McsLock<T, R> { }
+
+impl<T: ?Sized, R> core::panic::RefUnwindSafe for McsLock<T, R> { }
+
+unsafe impl<T: ?Sized + Send, R> Send for McsLock<T, R> { }
+
+unsafe impl<T: ?Sized + Send, R> Sync for McsLock<T, R> { }
+
+impl<T, R> From<T> for McsLock<T, R> {
+    fn from(t: T) -> McsLock<T, R> {
+        McsLock::with_relax(t)
+    }
+}
+
+impl<T: Default, R> Default for McsLock<T, R> {
+    fn default() -> McsLock<T, R> {
+        McsLock::with_relax(T::default())
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug, R> core::fmt::Debug for McsLock<T, R> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.try_lock() {
+            Ok(guard) => f.debug_struct("McsLock")
+                .field("data", &&*guard)
+                .finish(),
+            Err(poison::TryLockError::Poisoned(err)) => {
+                f.debug_struct("McsLock")
+                    .field("data", &&**err.get_ref())
+                    .finish()
+            },
+            Err(poison::TryLockError::WouldBlock) => {
+                struct LockedPlaceholder;
+
+                impl core::fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self,
+                           f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                f.debug_struct("McsLock")
+                    .field("data", &LockedPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+pub struct McsLockGuard<'a, T: ?Sized + 'a, R: 'a = relax::Spin> {
+    lock: &'a McsLock<T, R>,
+    poison: poison::Guard,
+    // boxed so the node our successor links to stays put when the guard is
+    // moved; only `None` while dropping
+    node: Option<Box<McsNode>>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync, R> Sync for McsLockGuard<'a, T, R> { }
+
+impl<'a, T: ?Sized, R> core::ops::Deref for McsLockGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized, R> core::ops::DerefMut for McsLockGuard<'a, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        match unsafe { self.lock.data.get().as_mut() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for McsLockGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("McsLockGuard")
+            .field("lock", &self.lock)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, R> core::fmt::Display
+    for McsLockGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, R> Drop for McsLockGuard<'a, T, R> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+
+        if let Some(node) = self.node.take() {
+            unsafe { self.lock.raw_unlock(&node); }
+
+            give_node(node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {relax, McsLock, TryLockError};
+
+    #[test]
+    fn already_locked() {
+        let lock = McsLock::new(());
+        assert!(!lock.is_poisoned());
+
+        let guard = lock.lock();
+        assert!(guard.is_ok());
+
+        match lock.try_lock() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        drop(guard);
+        assert!(lock.try_lock().is_ok());
+    }
+
+    #[test]
+    fn poisoned() {
+        let lock = McsLock::new(());
+        assert!(!lock.is_poisoned());
+
+        let result = std::panic::catch_unwind(|| {
+            if let Ok(_guard) = lock.lock() {
+                panic!();
+            }
+        });
+
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+        assert!(lock.into_inner().is_err());
+    }
+
+    #[test]
+    fn nested_and_contended() {
+        static NUM_THREADS: usize = 8;
+        static NUM_ITERS: usize = 1000;
+
+        // yield so that FIFO handoff doesn't stall on preempted waiters when
+        // there are fewer cores than threads
+        let locks = std::sync::Arc::new((
+            McsLock::<_, relax::SpinThenYield>::with_relax(0),
+            McsLock::<_, relax::SpinThenYield>::with_relax(0),
+        ));
+
+        let threads: Vec<_> = (0..NUM_THREADS).map(|i| {
+            let cloned = locks.clone();
+
+            std::thread::spawn(move || {
+                for _ in 0..NUM_ITERS {
+                    // take them in both orders of release to exercise the
+                    // node cache
+                    let mut outer = cloned.0.lock().unwrap();
+                    let mut inner = cloned.1.lock().unwrap();
+
+                    *outer += 1;
+                    *inner += 1;
+
+                    if i % 2 == 0 {
+                        drop(outer);
+                        drop(inner);
+                    } else {
+                        drop(inner);
+                        drop(outer);
+                    }
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(*locks.0.lock().unwrap(), NUM_THREADS * NUM_ITERS);
+        assert_eq!(*locks.1.lock().unwrap(), NUM_THREADS * NUM_ITERS);
+    }
+}
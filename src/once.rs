@@ -0,0 +1,469 @@
+use poison;
+use relax;
+use sync;
+use AtomicFlag;
+
+// runs an initializer exactly once. callers that arrive while it runs spin
+// until it finishes. if the initializer panics, the once is poisoned like a
+// lock whose guard was dropped during a panic, and later calls panic rather
+// than run it again until the poison is cleared
+pub struct SpinOnce<R = relax::Spin> {
+    is_running: sync::atomic::AtomicBool,
+    is_completed: sync::atomic::AtomicBool,
+    poison: poison::Flag,
+    relax: core::marker::PhantomData<R>,
+}
+
+impl SpinOnce {
+    loom_const_fn! {
+        pub fn new() -> SpinOnce {
+            SpinOnce::with_relax()
+        }
+    }
+}
+
+impl<R> SpinOnce<R> {
+    loom_const_fn! {
+        pub fn with_relax() -> SpinOnce<R> {
+            SpinOnce {
+                is_running: sync::atomic::AtomicBool::new(false),
+                is_completed: sync::atomic::AtomicBool::new(false),
+                poison: poison::Flag::new(),
+                relax: core::marker::PhantomData,
+            }
+        }
+    }
+
+    pub fn call_once<F: FnOnce()>(&self, f: F) where R: relax::Relax {
+        let result: Result<(), core::convert::Infallible> =
+            self.try_call_once(|| {
+                f();
+
+                Ok(())
+            });
+
+        if let Err(never) = result {
+            match never { }
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.is_completed.load(sync::atomic::Ordering::Acquire)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.poison.info()
+    }
+
+    // lets the next caller run its initializer again
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+
+    // an initializer that returns an error leaves the once incomplete, but
+    // not poisoned, for the next caller to try again
+    fn try_call_once<E, F>(&self, f: F) -> Result<(), E>
+    where
+        F: FnOnce() -> Result<(), E>,
+        R: relax::Relax,
+    {
+        if self.is_completed() {
+            return Ok(());
+        }
+
+        let mut relax = R::default();
+
+        while self.is_running.test_and_set(sync::atomic::Ordering::Acquire) {
+            while self.is_running.test(sync::atomic::Ordering::Relaxed) {
+                relax.relax();
+            }
+        }
+
+        let _running = Running{ once: self, poison: self.poison.guard() };
+
+        if self.is_completed.load(sync::atomic::Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        if self.poison.get() {
+            panic!("SpinOnce instance has previously been poisoned");
+        }
+
+        f()?;
+        self.is_completed.store(true, sync::atomic::Ordering::Release);
+
+        Ok(())
+    }
+}
+
+impl<R> core::panic::UnwindSafe for SpinOnce<R> { }
+
+impl<R> core::panic::RefUnwindSafe for SpinOnce<R> { }
+
+unsafe impl<R> Send for SpinOnce<R> { }
+
+unsafe impl<R> Sync for SpinOnce<R> { }
+
+impl<R> Default for SpinOnce<R> {
+    fn default() -> SpinOnce<R> {
+        SpinOnce::with_relax()
+    }
+}
+
+impl<R> core::fmt::Debug for SpinOnce<R> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("SpinOnce")
+            .field("completed", &self.is_completed())
+            .field("poisoned", &self.is_poisoned())
+            .finish()
+    }
+}
+
+// held while an initializer runs, so that it poisons the once if the
+// initializer unwinds, the same way SpinlockGuard poisons its lock
+struct Running<'a, R: 'a> {
+    once: &'a SpinOnce<R>,
+    poison: poison::Guard,
+}
+
+impl<'a, R> Drop for Running<'a, R> {
+    fn drop(&mut self) {
+        self.once.poison.done(&self.poison);
+        self.once.is_running.clear(sync::atomic::Ordering::Release);
+    }
+}
+
+pub struct SpinOnceCell<T, R = relax::Spin> {
+    once: SpinOnce<R>,
+    value: core::cell::UnsafeCell<core::mem::MaybeUninit<T>>,
+}
+
+impl<T> SpinOnceCell<T> {
+    loom_const_fn! {
+        pub fn new() -> SpinOnceCell<T> {
+            SpinOnceCell::with_relax()
+        }
+    }
+}
+
+impl<T, R> SpinOnceCell<T, R> {
+    loom_const_fn! {
+        pub fn with_relax() -> SpinOnceCell<T, R> {
+            SpinOnceCell {
+                once: SpinOnce::with_relax(),
+                value: core::cell::UnsafeCell::new(core::mem::MaybeUninit::uninit()),
+            }
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if !self.once.is_completed() {
+            return None;
+        }
+
+        Some(unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if !self.once.is_completed() {
+            return None;
+        }
+
+        Some(unsafe { (*self.value.get()).assume_init_mut() })
+    }
+
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+        R: relax::Relax,
+    {
+        let result: Result<&T, core::convert::Infallible> =
+            self.get_or_try_init(|| Ok(f()));
+
+        match result {
+            Ok(value) => value,
+            Err(never) => match never { },
+        }
+    }
+
+    pub fn get_or_try_init<E, F>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        R: relax::Relax,
+    {
+        self.once.try_call_once(|| {
+            let value = f()?;
+            unsafe { (*self.value.get()).write(value); }
+
+            Ok(())
+        })?;
+
+        Ok(unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    pub fn into_inner(mut self) -> Option<T> {
+        if !self.once.is_completed() {
+            return None;
+        }
+
+        // keep our Drop from dropping the value we just moved out
+        self.once = SpinOnce::with_relax();
+
+        Some(unsafe { (*self.value.get()).assume_init_read() })
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.once.is_poisoned()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.once.poison_info()
+    }
+
+    pub fn clear_poison(&self) {
+        self.once.clear_poison();
+    }
+}
+
+impl<T, R> core::panic::UnwindSafe for SpinOnceCell<T, R> { }
+
+impl<T, R> core::panic::RefUnwindSafe for SpinOnceCell<T, R> { }
+
+unsafe impl<T: Send, R> Send for SpinOnceCell<T, R> { }
+
+unsafe impl<T: Send + Sync, R> Sync for SpinOnceCell<T, R> { }
+
+impl<T, R> Default for SpinOnceCell<T, R> {
+    fn default() -> SpinOnceCell<T, R> {
+        SpinOnceCell::with_relax()
+    }
+}
+
+impl<T, R> From<T> for SpinOnceCell<T, R> {
+    fn from(t: T) -> SpinOnceCell<T, R> {
+        let cell = SpinOnceCell::with_relax();
+        unsafe { (*cell.value.get()).write(t); }
+        cell.once.is_completed.store(true, sync::atomic::Ordering::Relaxed);
+
+        cell
+    }
+}
+
+impl<T: core::fmt::Debug, R> core::fmt::Debug for SpinOnceCell<T, R> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_struct("SpinOnceCell")
+                .field("data", value)
+                .finish(),
+            None => {
+                struct UninitPlaceholder;
+
+                impl core::fmt::Debug for UninitPlaceholder {
+                    fn fmt(&self,
+                           f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        f.write_str("<uninit>")
+                    }
+                }
+
+                f.debug_struct("SpinOnceCell")
+                    .field("data", &UninitPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+impl<T, R> Drop for SpinOnceCell<T, R> {
+    fn drop(&mut self) {
+        if self.once.is_completed.load(sync::atomic::Ordering::Relaxed) {
+            unsafe { (*self.value.get()).assume_init_drop(); }
+        }
+    }
+}
+
+// a value computed by `F` the first time it is dereferenced. a panicking
+// `F` poisons the cell, and since the initializer is gone by then, every
+// later dereference panics too
+pub struct SpinLazy<T, F = fn() -> T, R = relax::Spin> {
+    cell: SpinOnceCell<T, R>,
+    init: core::cell::Cell<Option<F>>,
+}
+
+impl<T, F> SpinLazy<T, F> {
+    loom_const_fn! {
+        pub fn new(f: F) -> SpinLazy<T, F> {
+            SpinLazy::with_relax(f)
+        }
+    }
+}
+
+impl<T, F, R> SpinLazy<T, F, R> {
+    loom_const_fn! {
+        pub fn with_relax(f: F) -> SpinLazy<T, F, R> {
+            SpinLazy {
+                cell: SpinOnceCell::with_relax(),
+                init: core::cell::Cell::new(Some(f)),
+            }
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.cell.get()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.cell.is_poisoned()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.cell.poison_info()
+    }
+}
+
+impl<T, F: FnOnce() -> T, R: relax::Relax> SpinLazy<T, F, R> {
+    // only the caller running the initializer touches `init`
+    pub fn force(this: &SpinLazy<T, F, R>) -> &T {
+        this.cell.get_or_init(|| match this.init.take() {
+            Some(f) => f(),
+            None => panic!("SpinLazy instance has previously been poisoned"),
+        })
+    }
+}
+
+impl<T, F, R> core::panic::UnwindSafe for SpinLazy<T, F, R> { }
+
+impl<T, F, R> core::panic::RefUnwindSafe for SpinLazy<T, F, R> { }
+
+unsafe impl<T: Send + Sync, F: Send, R> Sync for SpinLazy<T, F, R> { }
+
+impl<T: Default, R> Default for SpinLazy<T, fn() -> T, R> {
+    fn default() -> SpinLazy<T, fn() -> T, R> {
+        SpinLazy::with_relax(T::default)
+    }
+}
+
+impl<T, F: FnOnce() -> T, R: relax::Relax> core::ops::Deref
+    for SpinLazy<T, F, R>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        SpinLazy::force(self)
+    }
+}
+
+impl<T: core::fmt::Debug, F, R> core::fmt::Debug for SpinLazy<T, F, R> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("SpinLazy")
+            .field("cell", &self.cell)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use {SpinLazy, SpinOnce, SpinOnceCell};
+
+    #[test]
+    fn runs_once() {
+        static ONCE: SpinOnce = SpinOnce::new();
+        let mut count = 0;
+
+        ONCE.call_once(|| count += 1);
+        ONCE.call_once(|| count += 1);
+
+        assert_eq!(count, 1);
+        assert!(ONCE.is_completed());
+        assert!(!ONCE.is_poisoned());
+    }
+
+    #[test]
+    fn failed_init_can_be_retried() {
+        let cell = SpinOnceCell::new();
+        assert!(cell.get().is_none());
+
+        assert_eq!(cell.get_or_try_init(|| Err(())), Err(()));
+        assert!(cell.get().is_none());
+        assert!(!cell.is_poisoned());
+
+        assert_eq!(cell.get_or_try_init(|| Ok::<_, ()>(1)), Ok(&1));
+        assert_eq!(*cell.get_or_init(|| 2), 1);
+        assert_eq!(cell.into_inner(), Some(1));
+    }
+
+    #[test]
+    fn lazy_in_a_static() {
+        static VALUE: SpinLazy<usize> = SpinLazy::new(|| 6 * 7);
+
+        assert!(VALUE.get().is_none());
+        assert_eq!(*VALUE, 42);
+        assert_eq!(VALUE.get(), Some(&42));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn poisoned_until_cleared() {
+        let cell = SpinOnceCell::new();
+
+        let result = std::panic::catch_unwind(|| {
+            cell.get_or_init(|| panic!());
+        });
+
+        assert!(result.is_err());
+        assert!(cell.is_poisoned());
+
+        let result = std::panic::catch_unwind(|| {
+            cell.get_or_init(|| 1);
+        });
+
+        assert!(result.is_err());
+        assert!(cell.get().is_none());
+
+        cell.clear_poison();
+        assert!(!cell.is_poisoned());
+        assert_eq!(*cell.get_or_init(|| 1), 1);
+
+        let lazy = SpinLazy::new(|| -> usize { panic!() });
+
+        assert!(std::panic::catch_unwind(|| *lazy).is_err());
+        assert!(lazy.is_poisoned());
+        assert!(std::panic::catch_unwind(|| *lazy).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn initialized_once_under_contention() {
+        static NUM_THREADS: usize = 8;
+        static CELL: SpinOnceCell<usize, ::relax::SpinThenYield> =
+            SpinOnceCell::with_relax();
+        static CALLS: std::sync::atomic::AtomicUsize =
+            std::sync::atomic::AtomicUsize::new(0);
+
+        let threads: std::vec::Vec<_> = (0..NUM_THREADS).map(|i| {
+            std::thread::spawn(move || {
+                *CELL.get_or_init(|| {
+                    CALLS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    std::thread::yield_now();
+
+                    i
+                })
+            })
+        }).collect();
+
+        let values: std::vec::Vec<_> = threads.into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+
+        assert_eq!(CALLS.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert!(values.iter().all(|&value| value == values[0]));
+    }
+}
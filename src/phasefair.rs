@@ -0,0 +1,473 @@
+use cache_padded::CachePadded;
+use poison;
+use relax;
+use sync;
+
+// Brandenburg and Anderson's ticket-based phase-fair reader-writer lock.
+// reader and writer phases alternate whenever both are waiting: a writer
+// waits for at most one read phase to finish, and a reader for at most one
+// write phase, so neither side can starve the other. writers are served in
+// FIFO order among themselves.
+//
+// poisoning follows SpinRwLock: only a panicking writer poisons the lock
+pub struct PhaseFairRwLock<T: ?Sized, R = relax::Spin> {
+    // readers that have entered or are waiting to, times `READER`, plus
+    // `PRESENT` and the writer's `PHASE` while a writer is waiting or inside
+    reader_in: CachePadded<sync::atomic::AtomicUsize>,
+    // readers that have left, times `READER`
+    reader_out: CachePadded<sync::atomic::AtomicUsize>,
+    writer_in: CachePadded<sync::atomic::AtomicUsize>,
+    writer_out: CachePadded<sync::atomic::AtomicUsize>,
+    poison: poison::Flag,
+    relax: core::marker::PhantomData<R>,
+    data: core::cell::UnsafeCell<T>,
+}
+
+const PHASE: usize = 1;
+const PRESENT: usize = 2;
+const WRITER_BITS: usize = PHASE | PRESENT;
+const READER: usize = 4;
+
+impl<T> PhaseFairRwLock<T> {
+    loom_const_fn! {
+        pub fn new(t: T) -> PhaseFairRwLock<T> {
+            PhaseFairRwLock::with_relax(t)
+        }
+    }
+}
+
+impl<T, R> PhaseFairRwLock<T, R> {
+    loom_const_fn! {
+        pub fn with_relax(t: T) -> PhaseFairRwLock<T, R> {
+            PhaseFairRwLock {
+                reader_in: CachePadded::new(sync::atomic::AtomicUsize::new(0)),
+                reader_out: CachePadded::new(sync::atomic::AtomicUsize::new(0)),
+                writer_in: CachePadded::new(sync::atomic::AtomicUsize::new(0)),
+                writer_out: CachePadded::new(sync::atomic::AtomicUsize::new(0)),
+                poison: poison::Flag::new(),
+                relax: core::marker::PhantomData,
+                data: core::cell::UnsafeCell::new(t),
+            }
+        }
+    }
+}
+
+impl<T: ?Sized, R> PhaseFairRwLock<T, R> {
+    pub fn read(&self) -> poison::LockResult<PhaseFairRwLockReadGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        unsafe { self.raw_read(); }
+
+        self.poison.result(PhaseFairRwLockReadGuard{ lock: self })
+    }
+
+    pub fn try_read(
+        &self
+    ) -> poison::TryLockResult<PhaseFairRwLockReadGuard<'_, T, R>> {
+        if unsafe { !self.raw_try_read() } {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(PhaseFairRwLockReadGuard{ lock: self })?)
+    }
+
+    pub fn write(&self) -> poison::LockResult<PhaseFairRwLockWriteGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        unsafe { self.raw_write(); }
+
+        self.poison.result(PhaseFairRwLockWriteGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })
+    }
+
+    pub fn try_write(
+        &self
+    ) -> poison::TryLockResult<PhaseFairRwLockWriteGuard<'_, T, R>> {
+        if unsafe { !self.raw_try_write() } {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(PhaseFairRwLockWriteGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.poison.info()
+    }
+
+    pub fn into_inner(self) -> poison::LockResult<T> where T: Sized {
+        let PhaseFairRwLock { poison, data, .. } = self;
+
+        poison.result(data.into_inner())
+    }
+
+    pub fn get_mut(&mut self) -> poison::LockResult<&mut T> {
+        let data = unsafe { &mut *self.data.get() };
+
+        self.poison.result(data)
+    }
+
+    // if a writer is present, wait for its phase to end rather than for the
+    // lock to be free of writers, so that the next writer can't cut in
+    unsafe fn raw_read(&self) where R: relax::Relax {
+        let writer = self.reader_in.fetch_add(
+            READER,
+            sync::atomic::Ordering::Acquire
+        ) & WRITER_BITS;
+
+        if writer == 0 {
+            return;
+        }
+
+        let mut relax = R::default();
+
+        while self.reader_in.load(sync::atomic::Ordering::Acquire) & WRITER_BITS
+            == writer
+        {
+            relax.relax();
+        }
+    }
+
+    unsafe fn raw_try_read(&self) -> bool {
+        let reader_in = self.reader_in.load(sync::atomic::Ordering::Relaxed);
+
+        reader_in & WRITER_BITS == 0 && self.reader_in.compare_exchange(
+            reader_in,
+            reader_in.wrapping_add(READER),
+            sync::atomic::Ordering::Acquire,
+            sync::atomic::Ordering::Relaxed
+        ).is_ok()
+    }
+
+    unsafe fn raw_read_unlock(&self) {
+        self.reader_out.fetch_add(READER, sync::atomic::Ordering::Release);
+    }
+
+    unsafe fn raw_write(&self) where R: relax::Relax {
+        let mut relax = R::default();
+        let ticket = self.writer_in.fetch_add(1, sync::atomic::Ordering::Relaxed);
+
+        while self.writer_out.load(sync::atomic::Ordering::Acquire) != ticket {
+            relax.relax();
+        }
+
+        // from here on, arriving readers wait for our phase to end
+        let readers = self.reader_in.fetch_add(
+            PRESENT | (ticket & PHASE),
+            sync::atomic::Ordering::Acquire
+        );
+
+        while self.reader_out.load(sync::atomic::Ordering::Acquire) != readers {
+            relax.relax();
+        }
+    }
+
+    // takes the next writer ticket only if no writer holds or is waiting for
+    // it, then closes the lock to readers only if none are inside
+    unsafe fn raw_try_write(&self) -> bool {
+        let ticket = self.writer_out.load(sync::atomic::Ordering::Acquire);
+
+        if self.writer_in.compare_exchange(
+            ticket,
+            ticket.wrapping_add(1),
+            sync::atomic::Ordering::Relaxed,
+            sync::atomic::Ordering::Relaxed
+        ).is_err() {
+            return false;
+        }
+
+        let readers = self.reader_in.load(sync::atomic::Ordering::Relaxed);
+
+        if self.reader_out.load(sync::atomic::Ordering::Acquire) != readers
+            || self.reader_in.compare_exchange(
+                readers,
+                readers | PRESENT | (ticket & PHASE),
+                sync::atomic::Ordering::Acquire,
+                sync::atomic::Ordering::Relaxed
+            ).is_err()
+        {
+            // hand our ticket on to whoever queued up behind it
+            self.writer_out.fetch_add(1, sync::atomic::Ordering::Release);
+
+            return false;
+        }
+
+        true
+    }
+
+    unsafe fn raw_write_unlock(&self) {
+        self.reader_in.fetch_and(!WRITER_BITS, sync::atomic::Ordering::Release);
+        self.writer_out.fetch_add(1, sync::atomic::Ordering::Release);
+    }
+}
+
+impl<T: ?Sized, R> core::panic::UnwindSafe for PhaseFairRwLock<T, R> { }
+
+impl<T: ?Sized, R> core::panic::RefUnwindSafe for PhaseFairRwLock<T, R> { }
+
+unsafe impl<T: ?Sized + Send, R> Send for PhaseFairRwLock<T, R> { }
+
+unsafe impl<T: ?Sized + Send + Sync, R> Sync for PhaseFairRwLock<T, R> { }
+
+impl<T, R> From<T> for PhaseFairRwLock<T, R> {
+    fn from(t: T) -> PhaseFairRwLock<T, R> {
+        PhaseFairRwLock::with_relax(t)
+    }
+}
+
+impl<T: Default, R> Default for PhaseFairRwLock<T, R> {
+    fn default() -> PhaseFairRwLock<T, R> {
+        PhaseFairRwLock::with_relax(T::default())
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for PhaseFairRwLock<T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.try_read() {
+            Ok(guard) => f.debug_struct("PhaseFairRwLock")
+                .field("data", &&*guard)
+                .finish(),
+            Err(poison::TryLockError::Poisoned(err)) => {
+                f.debug_struct("PhaseFairRwLock")
+                    .field("data", &&**err.get_ref())
+                    .finish()
+            },
+            Err(poison::TryLockError::WouldBlock) => {
+                struct LockedPlaceholder;
+
+                impl core::fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self,
+                           f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                f.debug_struct("PhaseFairRwLock")
+                    .field("data", &LockedPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+pub struct PhaseFairRwLockReadGuard<'a, T: ?Sized + 'a, R: 'a = relax::Spin> {
+    lock: &'a PhaseFairRwLock<T, R>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync, R> Sync
+    for PhaseFairRwLockReadGuard<'a, T, R> { }
+
+impl<'a, T: ?Sized, R> core::ops::Deref for PhaseFairRwLockReadGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for PhaseFairRwLockReadGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("PhaseFairRwLockReadGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, R> core::fmt::Display
+    for PhaseFairRwLockReadGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, R> Drop for PhaseFairRwLockReadGuard<'a, T, R> {
+    fn drop(&mut self) {
+        unsafe { self.lock.raw_read_unlock(); }
+    }
+}
+
+pub struct PhaseFairRwLockWriteGuard<'a, T: ?Sized + 'a, R: 'a = relax::Spin> {
+    lock: &'a PhaseFairRwLock<T, R>,
+    poison: poison::Guard,
+}
+
+unsafe impl<'a, T: ?Sized + Sync, R> Sync
+    for PhaseFairRwLockWriteGuard<'a, T, R> { }
+
+impl<'a, T: ?Sized, R> core::ops::Deref for PhaseFairRwLockWriteGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized, R> core::ops::DerefMut
+    for PhaseFairRwLockWriteGuard<'a, T, R>
+{
+    fn deref_mut(&mut self) -> &mut T {
+        match unsafe { self.lock.data.get().as_mut() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for PhaseFairRwLockWriteGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("PhaseFairRwLockWriteGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, R> core::fmt::Display
+    for PhaseFairRwLockWriteGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, R> Drop for PhaseFairRwLockWriteGuard<'a, T, R> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+
+        unsafe { self.lock.raw_write_unlock(); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use {PhaseFairRwLock, TryLockError};
+
+    #[test]
+    fn readers_share_writers_exclude() {
+        let lock = PhaseFairRwLock::new(1);
+
+        let first = lock.read().unwrap();
+        let second = lock.try_read().unwrap();
+        assert_eq!(*first + *second, 2);
+
+        match lock.try_write() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        drop(first);
+        drop(second);
+
+        let mut writer = lock.try_write().unwrap();
+        *writer += 1;
+
+        assert!(lock.try_read().is_err());
+        assert!(lock.try_write().is_err());
+        drop(writer);
+
+        assert_eq!(*lock.read().unwrap(), 2);
+        assert_eq!(*lock.write().unwrap(), 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn poisoned_by_writers_only() {
+        let lock = PhaseFairRwLock::new(());
+
+        let result = std::panic::catch_unwind(|| {
+            let _guard = lock.read().unwrap();
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert!(!lock.is_poisoned());
+
+        let result = std::panic::catch_unwind(|| {
+            let _guard = lock.write().unwrap();
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+        assert!(lock.read().is_err());
+        assert!(lock.into_inner().is_err());
+    }
+
+    // each reader holds its read across several reschedules, so with a few
+    // of them there is almost always one inside. a reader-preferring lock
+    // lets them keep the writers out indefinitely
+    #[cfg(feature = "std")]
+    #[test]
+    fn writers_progress_under_read_load() {
+        static NUM_READERS: usize = 4;
+        static NUM_WRITERS: usize = 2;
+        static NUM_WRITES: usize = 100;
+
+        let lock = std::sync::Arc::new(
+            PhaseFairRwLock::<_, ::relax::SpinThenYield>::with_relax(0)
+        );
+        let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let readers: std::vec::Vec<_> = (0..NUM_READERS).map(|_| {
+            let lock = lock.clone();
+            let done = done.clone();
+
+            std::thread::spawn(move || {
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    let _guard = lock.read().unwrap();
+
+                    for _ in 0..4 {
+                        std::thread::yield_now();
+                    }
+                }
+            })
+        }).collect();
+
+        let writers: std::vec::Vec<_> = (0..NUM_WRITERS).map(|_| {
+            let lock = lock.clone();
+
+            std::thread::spawn(move || {
+                for _ in 0..NUM_WRITES {
+                    *lock.write().unwrap() += 1;
+                }
+            })
+        }).collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(*lock.read().unwrap(), NUM_WRITERS * NUM_WRITES);
+    }
+}
@@ -0,0 +1,475 @@
+#[cfg(feature = "std")]
+pub use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+
+#[cfg(not(feature = "std"))]
+pub use self::core_only::{LockResult, PoisonError, TryLockError, TryLockResult};
+
+use sync;
+
+pub struct Flag {
+    failed: sync::atomic::AtomicBool,
+    // what caused the lock to be poisoned, if it is
+    #[cfg(feature = "poison-info")]
+    info: std::sync::Mutex<Option<PoisonInfo>>,
+}
+
+impl Flag {
+    loom_const_fn! {
+        pub fn new() -> Flag {
+            Flag {
+                failed: sync::atomic::AtomicBool::new(false),
+                #[cfg(feature = "poison-info")]
+                info: std::sync::Mutex::new(None),
+            }
+        }
+    }
+
+    // the lock's own acquire and release orderings publish this flag
+    pub fn get(&self) -> bool {
+        self.failed.load(sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn guard(&self) -> Guard {
+        Guard::new()
+    }
+
+    // with poison-info, the flag only changes while the info is locked, so
+    // `result_with_info` sees the two agree
+    pub fn done(&self, guard: &Guard) {
+        if guard.panicked() {
+            #[cfg(feature = "poison-info")]
+            let _info = self.record(PoisonInfo::capture());
+
+            self.failed.store(true, sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    pub fn clear(&self) {
+        #[cfg(feature = "poison-info")]
+        let mut info = self.lock_info();
+
+        #[cfg(feature = "poison-info")]
+        info.take();
+
+        self.failed.store(false, sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn result<G>(&self, guard: G) -> LockResult<G> {
+        if self.get() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn result_with_info<G>(&self, guard: G) -> LockResultWithInfo<G> {
+        let info = self.lock_info();
+
+        match *info {
+            Some(ref info) if self.get() => {
+                Err(PoisonErrorWithInfo { guard, info: info.clone() })
+            },
+            _ => Ok(guard),
+        }
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn info(&self) -> Option<PoisonInfo> {
+        self.lock_info().clone()
+    }
+
+    // keeps the first panic; any later ones found the lock already poisoned
+    #[cfg(feature = "poison-info")]
+    fn record(
+        &self,
+        info: PoisonInfo
+    ) -> std::sync::MutexGuard<'_, Option<PoisonInfo>> {
+        let mut recorded = self.lock_info();
+
+        if recorded.is_none() {
+            *recorded = Some(info);
+        }
+
+        recorded
+    }
+
+    #[cfg(feature = "poison-info")]
+    fn lock_info(&self) -> std::sync::MutexGuard<'_, Option<PoisonInfo>> {
+        self.info.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// Which thread poisoned a lock, when, and with what panic.
+///
+/// Returned by each lock's `poison_info` while the lock is poisoned, and
+/// carried by the errors from Spinlock's `_with_info` methods. Only
+/// the first panic is kept until the poison is cleared, since any later ones
+/// found the lock already poisoned.
+#[cfg(feature = "poison-info")]
+#[derive(Clone, Debug)]
+pub struct PoisonInfo {
+    thread_id: std::thread::ThreadId,
+    thread_name: Option<std::string::String>,
+    time: std::time::SystemTime,
+    message: Option<std::string::String>,
+    location: Option<std::string::String>,
+}
+
+#[cfg(feature = "poison-info")]
+impl PoisonInfo {
+    /// The thread that panicked while holding the lock.
+    pub fn thread_id(&self) -> std::thread::ThreadId {
+        self.thread_id
+    }
+
+    /// The name of that thread, if it has one.
+    pub fn thread_name(&self) -> Option<&str> {
+        self.thread_name.as_ref().map(|name| &name[..])
+    }
+
+    /// When the guard was dropped by the panic.
+    pub fn time(&self) -> std::time::SystemTime {
+        self.time
+    }
+
+    /// The panic's message, if its payload was a string.
+    ///
+    /// The message and location come from a panic hook this crate installs
+    /// the first time a lock is taken, so they are missing if another hook
+    /// replaced it later. `resume_unwind` skips hooks, so a lock poisoned by
+    /// one shows the thread's previous panic, if any.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(|message| &message[..])
+    }
+
+    /// Where the panic happened, as `file:line:column`.
+    pub fn location(&self) -> Option<&str> {
+        self.location.as_ref().map(|location| &location[..])
+    }
+
+    // called while unwinding from the panic that poisons the lock
+    fn capture() -> PoisonInfo {
+        let thread = std::thread::current();
+        let (message, location) = hook::last_panic();
+
+        PoisonInfo {
+            thread_id: thread.id(),
+            thread_name: thread.name().map(std::string::String::from),
+            time: std::time::SystemTime::now(),
+            message,
+            location,
+        }
+    }
+}
+
+#[cfg(feature = "poison-info")]
+impl core::fmt::Display for PoisonInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "thread '{}'", self.thread_name().unwrap_or("<unnamed>"))?;
+
+        if let Some(location) = self.location() {
+            write!(f, " panicked at {}", location)?;
+        } else {
+            f.write_str(" panicked")?;
+        }
+
+        if let Some(message) = self.message() {
+            write!(f, ": {}", message)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What `Spinlock::lock_with_info` and `Spinlock::into_inner_with_info`
+/// return.
+#[cfg(feature = "poison-info")]
+pub type LockResultWithInfo<G> = Result<G, PoisonErrorWithInfo<G>>;
+
+/// What `Spinlock::try_lock_with_info` returns.
+#[cfg(feature = "poison-info")]
+pub type TryLockResultWithInfo<G> = Result<G, TryLockErrorWithInfo<G>>;
+
+/// A `PoisonError` that also says what poisoned the lock.
+///
+/// The info is read together with the poison flag, so it always describes
+/// the poison this error reports, even if the poison has been cleared since.
+/// It converts into a plain `PoisonError` with `From`.
+#[cfg(feature = "poison-info")]
+pub struct PoisonErrorWithInfo<G> {
+    guard: G,
+    info: PoisonInfo,
+}
+
+#[cfg(feature = "poison-info")]
+impl<G> PoisonErrorWithInfo<G> {
+    /// What poisoned the lock.
+    pub fn info(&self) -> &PoisonInfo {
+        &self.info
+    }
+
+    /// Returns the guard, or the data for `into_inner_with_info`.
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+
+    /// A reference to the guard, or the data for `into_inner_with_info`.
+    pub fn get_ref(&self) -> &G {
+        &self.guard
+    }
+
+    /// A mutable reference to the guard, or the data for
+    /// `into_inner_with_info`.
+    pub fn get_mut(&mut self) -> &mut G {
+        &mut self.guard
+    }
+}
+
+#[cfg(feature = "poison-info")]
+impl<G> From<PoisonErrorWithInfo<G>> for PoisonError<G> {
+    fn from(err: PoisonErrorWithInfo<G>) -> PoisonError<G> {
+        PoisonError::new(err.guard)
+    }
+}
+
+#[cfg(feature = "poison-info")]
+impl<G> core::fmt::Debug for PoisonErrorWithInfo<G> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("PoisonErrorWithInfo")
+            .field("info", &self.info)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "poison-info")]
+impl<G> core::fmt::Display for PoisonErrorWithInfo<G> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "poisoned lock: {}", self.info)
+    }
+}
+
+#[cfg(feature = "poison-info")]
+impl<G> std::error::Error for PoisonErrorWithInfo<G> { }
+
+/// A `TryLockError` whose poisoned case also says what poisoned the lock.
+#[cfg(feature = "poison-info")]
+pub enum TryLockErrorWithInfo<G> {
+    /// The lock was taken, but it is poisoned.
+    Poisoned(PoisonErrorWithInfo<G>),
+    /// The lock is held by someone else.
+    WouldBlock,
+}
+
+#[cfg(feature = "poison-info")]
+impl<G> From<PoisonErrorWithInfo<G>> for TryLockErrorWithInfo<G> {
+    fn from(err: PoisonErrorWithInfo<G>) -> TryLockErrorWithInfo<G> {
+        TryLockErrorWithInfo::Poisoned(err)
+    }
+}
+
+#[cfg(feature = "poison-info")]
+impl<G> From<TryLockErrorWithInfo<G>> for TryLockError<G> {
+    fn from(err: TryLockErrorWithInfo<G>) -> TryLockError<G> {
+        match err {
+            TryLockErrorWithInfo::Poisoned(err) => {
+                TryLockError::Poisoned(err.into())
+            },
+            TryLockErrorWithInfo::WouldBlock => TryLockError::WouldBlock,
+        }
+    }
+}
+
+#[cfg(feature = "poison-info")]
+impl<G> core::fmt::Debug for TryLockErrorWithInfo<G> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            TryLockErrorWithInfo::Poisoned(ref err) => {
+                f.debug_tuple("Poisoned").field(err).finish()
+            },
+            TryLockErrorWithInfo::WouldBlock => f.write_str("WouldBlock"),
+        }
+    }
+}
+
+#[cfg(feature = "poison-info")]
+impl<G> core::fmt::Display for TryLockErrorWithInfo<G> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            TryLockErrorWithInfo::Poisoned(ref err) => err.fmt(f),
+            TryLockErrorWithInfo::WouldBlock => {
+                f.write_str("try_lock failed because the operation would block")
+            },
+        }
+    }
+}
+
+#[cfg(feature = "poison-info")]
+impl<G> std::error::Error for TryLockErrorWithInfo<G> { }
+
+// a panic hook that remembers each thread's latest panic before handing it
+// on to whatever hook was installed before it. it is installed the first
+// time a guard is taken, so it is in place before any panic can poison
+#[cfg(feature = "poison-info")]
+mod hook {
+    use std::cell::RefCell;
+    use std::string::{String, ToString};
+
+    thread_local! {
+        static LAST_PANIC: RefCell<(Option<String>, Option<String>)> =
+            const { RefCell::new((None, None)) };
+    }
+
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+
+    pub fn install() {
+        // set_hook panics if the thread is panicking; a guard taken while
+        // unwinding can't be poisoned by it anyway
+        if std::thread::panicking() {
+            return;
+        }
+
+        INSTALL.call_once(|| {
+            let previous = std::panic::take_hook();
+
+            std::panic::set_hook(std::boxed::Box::new(move |info| {
+                let payload = info.payload();
+                let message = payload.downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned());
+                let location = info.location()
+                    .map(|location| location.to_string());
+
+                let _ = LAST_PANIC.try_with(|last| {
+                    *last.borrow_mut() = (message, location);
+                });
+
+                previous(info);
+            }));
+        });
+    }
+
+    pub fn last_panic() -> (Option<String>, Option<String>) {
+        LAST_PANIC.try_with(|last| last.borrow().clone())
+            .unwrap_or((None, None))
+    }
+}
+
+// whether the thread was already unwinding when it took the lock. like std,
+// only a panic that starts while the lock is held poisons it; a guard taken
+// and dropped by a destructor during unwinding leaves the lock alone
+pub struct Guard {
+    #[cfg(feature = "std")]
+    panicking: bool,
+}
+
+impl Guard {
+    pub fn new() -> Guard {
+        #[cfg(feature = "poison-info")]
+        hook::install();
+
+        Guard {
+            #[cfg(feature = "std")]
+            panicking: panicking(),
+        }
+    }
+
+    // whether a panic started while the guard was held
+    pub fn panicked(&self) -> bool {
+        !self.panicking() && panicking()
+    }
+
+    #[cfg(feature = "std")]
+    fn panicking(&self) -> bool {
+        self.panicking
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn panicking(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(feature = "std")]
+pub fn panicking() -> bool {
+    std::thread::panicking()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn panicking() -> bool {
+    false
+}
+
+#[cfg(not(feature = "std"))]
+mod core_only {
+    pub type LockResult<G> = Result<G, PoisonError<G>>;
+
+    pub type TryLockResult<G> = Result<G, TryLockError<G>>;
+
+    pub struct PoisonError<T> {
+        guard: T,
+    }
+
+    impl<T> PoisonError<T> {
+        pub fn new(guard: T) -> PoisonError<T> {
+            PoisonError { guard }
+        }
+
+        pub fn into_inner(self) -> T {
+            self.guard
+        }
+
+        pub fn get_ref(&self) -> &T {
+            &self.guard
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            &mut self.guard
+        }
+    }
+
+    impl<T> core::fmt::Debug for PoisonError<T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            f.debug_struct("PoisonError").finish_non_exhaustive()
+        }
+    }
+
+    impl<T> core::fmt::Display for PoisonError<T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            f.write_str("poisoned lock: another task failed inside")
+        }
+    }
+
+    pub enum TryLockError<T> {
+        Poisoned(PoisonError<T>),
+        WouldBlock,
+    }
+
+    impl<T> From<PoisonError<T>> for TryLockError<T> {
+        fn from(err: PoisonError<T>) -> TryLockError<T> {
+            TryLockError::Poisoned(err)
+        }
+    }
+
+    impl<T> core::fmt::Debug for TryLockError<T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            match *self {
+                TryLockError::Poisoned(..) => f.write_str("Poisoned(..)"),
+                TryLockError::WouldBlock => f.write_str("WouldBlock"),
+            }
+        }
+    }
+
+    impl<T> core::fmt::Display for TryLockError<T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            match *self {
+                TryLockError::Poisoned(..) => {
+                    f.write_str("poisoned lock: another task failed inside")
+                },
+                TryLockError::WouldBlock => {
+                    f.write_str("try_lock failed because the operation would block")
+                },
+            }
+        }
+    }
+}
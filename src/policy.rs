@@ -0,0 +1,223 @@
+use cancel;
+use poison;
+
+/// What a `Spinlock` does when a critical section panics.
+///
+/// The policy decides what the lock stores to remember a panic and what
+/// `lock`, `try_lock`, `into_inner` and `get_mut` return. It can't be
+/// implemented outside of this crate.
+pub trait Policy: sealed::Sealed {
+    #[doc(hidden)]
+    type Flag;
+
+    #[doc(hidden)]
+    type Guard;
+
+    /// What `lock`, `into_inner` and `get_mut` return.
+    type LockResult<G>;
+
+    /// What `try_lock` and the other acquisitions that can give up return.
+    type TryLockResult<G>;
+
+    /// What `lock_cancellable` returns.
+    type CancelLockResult<G>;
+
+    #[doc(hidden)]
+    #[cfg(not(loom))]
+    const FLAG_INIT: Self::Flag;
+
+    #[doc(hidden)]
+    #[cfg(loom)]
+    fn flag_init() -> Self::Flag;
+
+    #[doc(hidden)]
+    fn guard(flag: &Self::Flag) -> Self::Guard;
+
+    #[doc(hidden)]
+    fn done(flag: &Self::Flag, guard: &Self::Guard);
+
+    #[doc(hidden)]
+    fn is_poisoned(flag: &Self::Flag) -> bool;
+
+    #[doc(hidden)]
+    fn result<G>(flag: &Self::Flag, guard: G) -> Self::LockResult<G>;
+
+    #[doc(hidden)]
+    fn try_result<G>(
+        flag: &Self::Flag,
+        guard: Option<G>
+    ) -> Self::TryLockResult<G>;
+
+    #[doc(hidden)]
+    fn cancel_result<G>(
+        flag: &Self::Flag,
+        guard: Option<G>
+    ) -> Self::CancelLockResult<G>;
+}
+
+/// Poisons the lock like std's `Mutex` does, so every later acquisition
+/// returns a `PoisonError` until the poison is cleared.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Poison;
+
+impl Policy for Poison {
+    type Flag = poison::Flag;
+    type Guard = poison::Guard;
+    type LockResult<G> = poison::LockResult<G>;
+    type TryLockResult<G> = poison::TryLockResult<G>;
+    type CancelLockResult<G> = cancel::CancelLockResult<G>;
+
+    #[cfg(not(loom))]
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FLAG_INIT: poison::Flag = poison::Flag::new();
+
+    #[cfg(loom)]
+    fn flag_init() -> poison::Flag {
+        poison::Flag::new()
+    }
+
+    fn guard(flag: &poison::Flag) -> poison::Guard {
+        flag.guard()
+    }
+
+    fn done(flag: &poison::Flag, guard: &poison::Guard) {
+        flag.done(guard);
+    }
+
+    fn is_poisoned(flag: &poison::Flag) -> bool {
+        flag.get()
+    }
+
+    fn result<G>(flag: &poison::Flag, guard: G) -> poison::LockResult<G> {
+        flag.result(guard)
+    }
+
+    fn try_result<G>(
+        flag: &poison::Flag,
+        guard: Option<G>
+    ) -> poison::TryLockResult<G> {
+        match guard {
+            Some(guard) => Ok(flag.result(guard)?),
+            None => Err(poison::TryLockError::WouldBlock),
+        }
+    }
+
+    fn cancel_result<G>(
+        flag: &poison::Flag,
+        guard: Option<G>
+    ) -> cancel::CancelLockResult<G> {
+        match guard {
+            Some(guard) => Ok(flag.result(guard)?),
+            None => Err(cancel::CancelLockError::Cancelled),
+        }
+    }
+}
+
+/// Ignores panics. Acquisitions return the guard itself, or an `Option` of
+/// it if they can give up or be cancelled, and the lock stores nothing
+/// extra.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoPoison;
+
+impl Policy for NoPoison {
+    type Flag = ();
+    type Guard = ();
+    type LockResult<G> = G;
+    type TryLockResult<G> = Option<G>;
+    type CancelLockResult<G> = Option<G>;
+
+    #[cfg(not(loom))]
+    const FLAG_INIT: () = ();
+
+    #[cfg(loom)]
+    fn flag_init() { }
+
+    fn guard(_: &()) { }
+
+    fn done(_: &(), _: &()) { }
+
+    fn is_poisoned(_: &()) -> bool {
+        false
+    }
+
+    fn result<G>(_: &(), guard: G) -> G {
+        guard
+    }
+
+    fn try_result<G>(_: &(), guard: Option<G>) -> Option<G> {
+        guard
+    }
+
+    fn cancel_result<G>(_: &(), guard: Option<G>) -> Option<G> {
+        guard
+    }
+}
+
+/// Aborts the process if a panic starts while the lock is held, so nobody
+/// can ever see the data it left behind. Returns the same types as
+/// `NoPoison`.
+///
+/// Without the `std` feature there is no way to tell that a thread is
+/// panicking, and this behaves like `NoPoison`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Abort;
+
+impl Policy for Abort {
+    type Flag = ();
+    type Guard = poison::Guard;
+    type LockResult<G> = G;
+    type TryLockResult<G> = Option<G>;
+    type CancelLockResult<G> = Option<G>;
+
+    #[cfg(not(loom))]
+    const FLAG_INIT: () = ();
+
+    #[cfg(loom)]
+    fn flag_init() { }
+
+    fn guard(_: &()) -> poison::Guard {
+        poison::Guard::new()
+    }
+
+    fn done(_: &(), guard: &poison::Guard) {
+        if guard.panicked() {
+            abort();
+        }
+    }
+
+    fn is_poisoned(_: &()) -> bool {
+        false
+    }
+
+    fn result<G>(_: &(), guard: G) -> G {
+        guard
+    }
+
+    fn try_result<G>(_: &(), guard: Option<G>) -> Option<G> {
+        guard
+    }
+
+    fn cancel_result<G>(_: &(), guard: Option<G>) -> Option<G> {
+        guard
+    }
+}
+
+#[cfg(feature = "std")]
+fn abort() -> ! {
+    std::process::abort()
+}
+
+#[cfg(not(feature = "std"))]
+fn abort() -> ! {
+    unreachable!("a panic was noticed without std")
+}
+
+mod sealed {
+    pub trait Sealed { }
+
+    impl Sealed for super::Poison { }
+
+    impl Sealed for super::NoPoison { }
+
+    impl Sealed for super::Abort { }
+}
@@ -0,0 +1,369 @@
+use poison;
+use policy;
+use relax;
+use sync;
+use Spinlock;
+
+// a Spinlock that the thread holding it may lock again. it counts how many
+// guards that thread holds and only releases the lock when the last one is
+// dropped. since several guards can be live at once, they only hand out
+// shared references; put a RefCell inside for mutation.
+//
+// poisoning follows Spinlock: any guard dropped by a panic that started
+// while it was held poisons the lock
+pub struct ReentrantSpinlock<T: ?Sized, R = relax::Spin> {
+    lock: Spinlock<(), R, policy::NoPoison>,
+    // `thread_id()` of the thread holding `lock`, or zero
+    owner: sync::atomic::AtomicUsize,
+    // only touched by the owner
+    depth: core::cell::Cell<usize>,
+    poison: poison::Flag,
+    data: core::cell::UnsafeCell<T>,
+}
+
+// threads are numbered from one as they first lock a ReentrantSpinlock.
+// unlike the address of a thread local, a number is never reused by a later
+// thread
+static NEXT_THREAD_ID: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(1);
+
+thread_local! {
+    static THREAD_ID: usize =
+        NEXT_THREAD_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+}
+
+// a `usize` has no destructor, so std never tears `THREAD_ID` down and this
+// still works from other thread locals' destructors
+fn thread_id() -> usize {
+    THREAD_ID.with(|id| *id)
+}
+
+impl<T> ReentrantSpinlock<T> {
+    loom_const_fn! {
+        pub fn new(t: T) -> ReentrantSpinlock<T> {
+            ReentrantSpinlock::with_relax(t)
+        }
+    }
+}
+
+impl<T, R> ReentrantSpinlock<T, R> {
+    loom_const_fn! {
+        pub fn with_relax(t: T) -> ReentrantSpinlock<T, R> {
+            ReentrantSpinlock {
+                lock: Spinlock::with_relax(()),
+                owner: sync::atomic::AtomicUsize::new(0),
+                depth: core::cell::Cell::new(0),
+                poison: poison::Flag::new(),
+                data: core::cell::UnsafeCell::new(t),
+            }
+        }
+    }
+}
+
+impl<T: ?Sized, R> ReentrantSpinlock<T, R> {
+    pub fn lock(&self) -> poison::LockResult<ReentrantSpinlockGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        unsafe { self.raw_lock(); }
+
+        self.poison.result(ReentrantSpinlockGuard::new(self))
+    }
+
+    pub fn try_lock(
+        &self
+    ) -> poison::TryLockResult<ReentrantSpinlockGuard<'_, T, R>> {
+        if unsafe { !self.raw_try_lock() } {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(ReentrantSpinlockGuard::new(self))?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.poison.info()
+    }
+
+    pub fn into_inner(self) -> poison::LockResult<T> where T: Sized {
+        let ReentrantSpinlock { poison, data, .. } = self;
+
+        poison.result(data.into_inner())
+    }
+
+    pub fn get_mut(&mut self) -> poison::LockResult<&mut T> {
+        let data = unsafe { &mut *self.data.get() };
+
+        self.poison.result(data)
+    }
+
+    unsafe fn raw_lock(&self) where R: relax::Relax {
+        if !self.raw_relock() {
+            self.lock.raw_lock();
+            self.take_ownership();
+        }
+    }
+
+    unsafe fn raw_try_lock(&self) -> bool {
+        if self.raw_relock() {
+            return true;
+        }
+
+        if !self.lock.raw_try_lock() {
+            return false;
+        }
+
+        self.take_ownership();
+
+        true
+    }
+
+    // only our own thread ever stores our id, so if we read it back we still
+    // hold the lock and nobody else can be touching `depth`
+    unsafe fn raw_relock(&self) -> bool {
+        if self.owner.load(sync::atomic::Ordering::Relaxed) != thread_id() {
+            return false;
+        }
+
+        let depth = self.depth.get().checked_add(1)
+            .expect("ReentrantSpinlock locked too many times");
+        self.depth.set(depth);
+
+        true
+    }
+
+    unsafe fn take_ownership(&self) {
+        self.owner.store(thread_id(), sync::atomic::Ordering::Relaxed);
+        self.depth.set(1);
+    }
+
+    unsafe fn raw_unlock(&self) {
+        let depth = self.depth.get() - 1;
+        self.depth.set(depth);
+
+        if depth == 0 {
+            self.owner.store(0, sync::atomic::Ordering::Relaxed);
+            self.lock.raw_unlock();
+        }
+    }
+}
+
+impl<T: ?Sized, R> core::panic::UnwindSafe for ReentrantSpinlock<T, R> { }
+
+impl<T: ?Sized, R> core::panic::RefUnwindSafe for ReentrantSpinlock<T, R> { }
+
+unsafe impl<T: ?Sized + Send, R> Send for ReentrantSpinlock<T, R> { }
+
+unsafe impl<T: ?Sized + Send, R> Sync for ReentrantSpinlock<T, R> { }
+
+impl<T, R> From<T> for ReentrantSpinlock<T, R> {
+    fn from(t: T) -> ReentrantSpinlock<T, R> {
+        ReentrantSpinlock::with_relax(t)
+    }
+}
+
+impl<T: Default, R> Default for ReentrantSpinlock<T, R> {
+    fn default() -> ReentrantSpinlock<T, R> {
+        ReentrantSpinlock::with_relax(T::default())
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for ReentrantSpinlock<T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.try_lock() {
+            Ok(guard) => f.debug_struct("ReentrantSpinlock")
+                .field("data", &&*guard)
+                .finish(),
+            Err(poison::TryLockError::Poisoned(err)) => {
+                f.debug_struct("ReentrantSpinlock")
+                    .field("data", &&**err.get_ref())
+                    .finish()
+            },
+            Err(poison::TryLockError::WouldBlock) => {
+                struct LockedPlaceholder;
+
+                impl core::fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self,
+                           f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                f.debug_struct("ReentrantSpinlock")
+                    .field("data", &LockedPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+pub struct ReentrantSpinlockGuard<'a, T: ?Sized + 'a, R: 'a = relax::Spin> {
+    lock: &'a ReentrantSpinlock<T, R>,
+    poison: poison::Guard,
+    // the lock belongs to the thread that took it, so the guard can't be
+    // sent to another thread to be dropped there
+    not_send: core::marker::PhantomData<*const ()>,
+}
+
+impl<'a, T: ?Sized, R> ReentrantSpinlockGuard<'a, T, R> {
+    fn new(lock: &'a ReentrantSpinlock<T, R>) -> ReentrantSpinlockGuard<'a, T, R> {
+        ReentrantSpinlockGuard{
+            lock,
+            poison: lock.poison.guard(),
+            not_send: core::marker::PhantomData,
+        }
+    }
+}
+
+unsafe impl<'a, T: ?Sized + Sync, R> Sync
+    for ReentrantSpinlockGuard<'a, T, R> { }
+
+impl<'a, T: ?Sized, R> core::ops::Deref for ReentrantSpinlockGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for ReentrantSpinlockGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("ReentrantSpinlockGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, R> core::fmt::Display
+    for ReentrantSpinlockGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, R> Drop for ReentrantSpinlockGuard<'a, T, R> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+
+        unsafe { self.lock.raw_unlock(); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {relax, ReentrantSpinlock, TryLockError};
+
+    #[test]
+    fn released_by_the_last_guard() {
+        let lock = std::sync::Arc::new(ReentrantSpinlock::new(1));
+
+        let outer = lock.lock().unwrap();
+        let inner = lock.lock().unwrap();
+        let innermost = lock.try_lock().unwrap();
+        assert_eq!(*outer + *inner + *innermost, 3);
+
+        let try_elsewhere = {
+            let lock = lock.clone();
+
+            move || match lock.try_lock() {
+                Err(TryLockError::WouldBlock) => false,
+                Ok(_) => true,
+                Err(TryLockError::Poisoned(_)) => panic!("unexpected poison"),
+            }
+        };
+
+        drop(innermost);
+        drop(outer);
+        assert!(!std::thread::spawn(try_elsewhere.clone()).join().unwrap());
+
+        drop(inner);
+        assert!(std::thread::spawn(try_elsewhere).join().unwrap());
+    }
+
+    #[test]
+    fn poisoned() {
+        let lock = ReentrantSpinlock::new(());
+
+        let result = std::panic::catch_unwind(|| {
+            let _outer = lock.lock().unwrap();
+            let _inner = lock.lock().unwrap();
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+        assert!(lock.try_lock().is_err());
+        assert!(lock.into_inner().is_err());
+    }
+
+    #[test]
+    fn locked_from_thread_local_destructor() {
+        static LOCK: ReentrantSpinlock<core::cell::Cell<usize>> =
+            ReentrantSpinlock::new(core::cell::Cell::new(0));
+
+        struct LocksOnDrop;
+
+        impl Drop for LocksOnDrop {
+            fn drop(&mut self) {
+                let outer = LOCK.lock().unwrap();
+                let inner = LOCK.lock().unwrap();
+                inner.set(outer.get() + 1);
+            }
+        }
+
+        thread_local! {
+            static LOCKS_ON_DROP: LocksOnDrop = const { LocksOnDrop };
+        }
+
+        std::thread::spawn(|| {
+            LOCKS_ON_DROP.with(|_| ());
+        }).join().unwrap();
+
+        assert_eq!(LOCK.lock().unwrap().get(), 1);
+    }
+
+    #[test]
+    fn nested_and_contended() {
+        static NUM_THREADS: usize = 8;
+        static NUM_ITERS: usize = 1000;
+        static LOCK: ReentrantSpinlock<
+            core::cell::Cell<usize>,
+            relax::SpinThenYield
+        > = ReentrantSpinlock::with_relax(core::cell::Cell::new(0));
+
+        fn increment(times: usize) {
+            let guard = LOCK.lock().unwrap();
+
+            if times > 0 {
+                guard.set(guard.get() + 1);
+                increment(times - 1);
+            }
+        }
+
+        let threads: std::vec::Vec<_> = (0..NUM_THREADS).map(|_| {
+            std::thread::spawn(|| {
+                for _ in 0..NUM_ITERS {
+                    increment(3);
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(LOCK.lock().unwrap().get(), NUM_THREADS * NUM_ITERS * 3);
+    }
+}
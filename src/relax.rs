@@ -0,0 +1,84 @@
+use sync;
+
+/// What a waiter does between failed acquisition attempts.
+///
+/// A fresh value is created with `Default` for every call to `lock`, so
+/// strategies may keep per-acquisition state such as a backoff counter.
+pub trait Relax: Default {
+    fn relax(&mut self);
+}
+
+/// Spins with a CPU hint between attempts.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Spin;
+
+impl Relax for Spin {
+    fn relax(&mut self) {
+        sync::spin_loop();
+    }
+}
+
+/// Spins for exponentially more iterations after each failed attempt, up to
+/// `2^BACKOFF_LIMIT` iterations.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Backoff {
+    step: u32,
+}
+
+const BACKOFF_LIMIT: u32 = 6;
+
+impl Relax for Backoff {
+    fn relax(&mut self) {
+        for _ in 0..(1u32 << self.step) {
+            sync::spin_loop();
+        }
+
+        if self.step < BACKOFF_LIMIT {
+            self.step += 1;
+        }
+    }
+}
+
+/// Backs off like `Backoff` until the cap is reached, then yields the
+/// thread to the scheduler on every attempt.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpinThenYield {
+    backoff: Backoff,
+}
+
+#[cfg(feature = "std")]
+impl Relax for SpinThenYield {
+    fn relax(&mut self) {
+        if self.backoff.step < BACKOFF_LIMIT {
+            self.backoff.relax();
+        } else {
+            std::thread::yield_now();
+        }
+    }
+}
+
+/// Retries immediately with no hint at all. Useful as a baseline when
+/// benchmarking the other strategies.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoRelax;
+
+impl Relax for NoRelax {
+    fn relax(&mut self) { }
+}
+
+#[cfg(test)]
+mod tests {
+    use relax::{Backoff, Relax, BACKOFF_LIMIT};
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut backoff = Backoff::default();
+
+        for _ in 0..(2 * BACKOFF_LIMIT) {
+            backoff.relax();
+        }
+
+        assert_eq!(backoff.step, BACKOFF_LIMIT);
+    }
+}
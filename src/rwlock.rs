@@ -0,0 +1,688 @@
+use poison;
+use relax;
+use sync;
+
+// any number of readers or a single writer. like std's RwLock, only a writer
+// that panics poisons the lock, since readers can't leave the data
+// half-modified.
+//
+// one of the readers may hold an upgradable read, which excludes writers and
+// other upgradable readers and can later be turned into a write without
+// letting anyone else in between. while an upgrade is waiting, new readers
+// wait too, so that the readers already inside drain and the upgrade can't be
+// starved
+pub struct SpinRwLock<T: ?Sized, R = relax::Spin> {
+    // `WRITER` if write-locked, otherwise the number of readers times
+    // `READER`, plus `UPGRADABLE` if an upgradable read is held and
+    // `UPGRADING` while it waits to upgrade
+    state: sync::atomic::AtomicUsize,
+    poison: poison::Flag,
+    relax: core::marker::PhantomData<R>,
+    data: core::cell::UnsafeCell<T>,
+}
+
+const WRITER: usize = 1;
+const UPGRADABLE: usize = 2;
+const UPGRADING: usize = 4;
+const READER: usize = 8;
+
+impl<T> SpinRwLock<T> {
+    loom_const_fn! {
+        pub fn new(t: T) -> SpinRwLock<T> {
+            SpinRwLock::with_relax(t)
+        }
+    }
+}
+
+impl<T, R> SpinRwLock<T, R> {
+    loom_const_fn! {
+        pub fn with_relax(t: T) -> SpinRwLock<T, R> {
+            SpinRwLock {
+                state: sync::atomic::AtomicUsize::new(0),
+                poison: poison::Flag::new(),
+                relax: core::marker::PhantomData,
+                data: core::cell::UnsafeCell::new(t),
+            }
+        }
+    }
+}
+
+impl<T: ?Sized, R> SpinRwLock<T, R> {
+    pub fn read(&self) -> poison::LockResult<SpinRwLockReadGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        unsafe { self.raw_read(); }
+
+        self.poison.result(SpinRwLockReadGuard{ lock: self })
+    }
+
+    pub fn try_read(&self) -> poison::TryLockResult<SpinRwLockReadGuard<'_, T, R>> {
+        if unsafe { !self.raw_try_read() } {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(SpinRwLockReadGuard{ lock: self })?)
+    }
+
+    pub fn upgradable_read(
+        &self
+    ) -> poison::LockResult<SpinRwLockUpgradableGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        unsafe { self.raw_upgradable_read(); }
+
+        self.poison.result(SpinRwLockUpgradableGuard{ lock: self })
+    }
+
+    pub fn try_upgradable_read(
+        &self
+    ) -> poison::TryLockResult<SpinRwLockUpgradableGuard<'_, T, R>> {
+        if unsafe { !self.raw_try_upgradable_read() } {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(SpinRwLockUpgradableGuard{ lock: self })?)
+    }
+
+    pub fn write(&self) -> poison::LockResult<SpinRwLockWriteGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        unsafe { self.raw_write(); }
+
+        self.poison.result(SpinRwLockWriteGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })
+    }
+
+    pub fn try_write(&self) -> poison::TryLockResult<SpinRwLockWriteGuard<'_, T, R>> {
+        if unsafe { !self.raw_try_write() } {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(SpinRwLockWriteGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.poison.info()
+    }
+
+    pub fn into_inner(self) -> poison::LockResult<T> where T: Sized {
+        let SpinRwLock { poison, data, .. } = self;
+
+        poison.result(data.into_inner())
+    }
+
+    pub fn get_mut(&mut self) -> poison::LockResult<&mut T> {
+        let data = unsafe { &mut *self.data.get() };
+
+        self.poison.result(data)
+    }
+
+    unsafe fn raw_read(&self) where R: relax::Relax {
+        let mut relax = R::default();
+
+        while !self.raw_try_read() {
+            while self.state.load(sync::atomic::Ordering::Relaxed)
+                & (WRITER | UPGRADING) != 0
+            {
+                relax.relax();
+            }
+        }
+    }
+
+    unsafe fn raw_try_read(&self) -> bool {
+        let state = self.state.load(sync::atomic::Ordering::Relaxed);
+
+        state & (WRITER | UPGRADING) == 0 && self.state.compare_exchange(
+            state,
+            state + READER,
+            sync::atomic::Ordering::Acquire,
+            sync::atomic::Ordering::Relaxed
+        ).is_ok()
+    }
+
+    unsafe fn raw_read_unlock(&self) {
+        self.state.fetch_sub(READER, sync::atomic::Ordering::Release);
+    }
+
+    unsafe fn raw_upgradable_read(&self) where R: relax::Relax {
+        let mut relax = R::default();
+
+        while !self.raw_try_upgradable_read() {
+            while self.state.load(sync::atomic::Ordering::Relaxed)
+                & (WRITER | UPGRADABLE) != 0
+            {
+                relax.relax();
+            }
+        }
+    }
+
+    unsafe fn raw_try_upgradable_read(&self) -> bool {
+        let state = self.state.load(sync::atomic::Ordering::Relaxed);
+
+        state & (WRITER | UPGRADABLE) == 0 && self.state.compare_exchange(
+            state,
+            state | UPGRADABLE,
+            sync::atomic::Ordering::Acquire,
+            sync::atomic::Ordering::Relaxed
+        ).is_ok()
+    }
+
+    unsafe fn raw_upgradable_unlock(&self) {
+        self.state.fetch_sub(UPGRADABLE, sync::atomic::Ordering::Release);
+    }
+
+    // waits for the remaining plain readers to leave, keeping new ones out
+    // until it's done. taking the write lock clears `UPGRADING` again
+    unsafe fn raw_upgrade(&self) where R: relax::Relax {
+        if self.raw_try_upgrade() {
+            return;
+        }
+
+        self.state.fetch_or(UPGRADING, sync::atomic::Ordering::Relaxed);
+
+        let mut relax = R::default();
+
+        while self.state.compare_exchange(
+            UPGRADABLE | UPGRADING,
+            WRITER,
+            sync::atomic::Ordering::Acquire,
+            sync::atomic::Ordering::Relaxed
+        ).is_err() {
+            while self.state.load(sync::atomic::Ordering::Relaxed)
+                != UPGRADABLE | UPGRADING
+            {
+                relax.relax();
+            }
+        }
+    }
+
+    unsafe fn raw_try_upgrade(&self) -> bool {
+        self.state.compare_exchange(
+            UPGRADABLE,
+            WRITER,
+            sync::atomic::Ordering::Acquire,
+            sync::atomic::Ordering::Relaxed
+        ).is_ok()
+    }
+
+    unsafe fn raw_write(&self) where R: relax::Relax {
+        let mut relax = R::default();
+
+        while !self.raw_try_write() {
+            while self.state.load(sync::atomic::Ordering::Relaxed) != 0 {
+                relax.relax();
+            }
+        }
+    }
+
+    unsafe fn raw_try_write(&self) -> bool {
+        self.state.compare_exchange(
+            0,
+            WRITER,
+            sync::atomic::Ordering::Acquire,
+            sync::atomic::Ordering::Relaxed
+        ).is_ok()
+    }
+
+    unsafe fn raw_write_unlock(&self) {
+        self.state.fetch_and(!WRITER, sync::atomic::Ordering::Release);
+    }
+
+    // nobody else can touch `state` while it's write-locked, so swapping the
+    // writer for a reader in one store lets nobody in between
+    unsafe fn raw_downgrade(&self, to: usize) {
+        self.state.store(to, sync::atomic::Ordering::Release);
+    }
+}
+
+impl<T: ?Sized, R> core::panic::UnwindSafe for SpinRwLock<T, R> { }
+
+impl<T: ?Sized, R> core::panic::RefUnwindSafe for SpinRwLock<T, R> { }
+
+unsafe impl<T: ?Sized + Send, R> Send for SpinRwLock<T, R> { }
+
+unsafe impl<T: ?Sized + Send + Sync, R> Sync for SpinRwLock<T, R> { }
+
+impl<T, R> From<T> for SpinRwLock<T, R> {
+    fn from(t: T) -> SpinRwLock<T, R> {
+        SpinRwLock::with_relax(t)
+    }
+}
+
+impl<T: Default, R> Default for SpinRwLock<T, R> {
+    fn default() -> SpinRwLock<T, R> {
+        SpinRwLock::with_relax(T::default())
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug, R> core::fmt::Debug for SpinRwLock<T, R> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.try_read() {
+            Ok(guard) => f.debug_struct("SpinRwLock")
+                .field("data", &&*guard)
+                .finish(),
+            Err(poison::TryLockError::Poisoned(err)) => {
+                f.debug_struct("SpinRwLock")
+                    .field("data", &&**err.get_ref())
+                    .finish()
+            },
+            Err(poison::TryLockError::WouldBlock) => {
+                struct LockedPlaceholder;
+
+                impl core::fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self,
+                           f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                f.debug_struct("SpinRwLock")
+                    .field("data", &LockedPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+pub struct SpinRwLockReadGuard<'a, T: ?Sized + 'a, R: 'a = relax::Spin> {
+    lock: &'a SpinRwLock<T, R>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync, R> Sync for SpinRwLockReadGuard<'a, T, R> { }
+
+impl<'a, T: ?Sized, R> core::ops::Deref for SpinRwLockReadGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for SpinRwLockReadGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("SpinRwLockReadGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, R> core::fmt::Display
+    for SpinRwLockReadGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, R> Drop for SpinRwLockReadGuard<'a, T, R> {
+    fn drop(&mut self) {
+        unsafe { self.lock.raw_read_unlock(); }
+    }
+}
+
+pub struct SpinRwLockUpgradableGuard<'a, T: ?Sized + 'a, R: 'a = relax::Spin> {
+    lock: &'a SpinRwLock<T, R>,
+}
+
+impl<'a, T: ?Sized, R> SpinRwLockUpgradableGuard<'a, T, R> {
+    pub fn upgrade(self) -> SpinRwLockWriteGuard<'a, T, R> where R: relax::Relax {
+        let lock = self.lock;
+        core::mem::forget(self);
+
+        unsafe { lock.raw_upgrade(); }
+
+        SpinRwLockWriteGuard{ lock, poison: lock.poison.guard() }
+    }
+
+    pub fn try_upgrade(self) -> Result<SpinRwLockWriteGuard<'a, T, R>, Self> {
+        if unsafe { !self.lock.raw_try_upgrade() } {
+            return Err(self);
+        }
+
+        let lock = self.lock;
+        core::mem::forget(self);
+
+        Ok(SpinRwLockWriteGuard{ lock, poison: lock.poison.guard() })
+    }
+
+    pub fn downgrade(self) -> SpinRwLockReadGuard<'a, T, R> {
+        let lock = self.lock;
+        core::mem::forget(self);
+
+        lock.state.fetch_add(READER - UPGRADABLE, sync::atomic::Ordering::Release);
+
+        SpinRwLockReadGuard{ lock }
+    }
+}
+
+unsafe impl<'a, T: ?Sized + Sync, R> Sync
+    for SpinRwLockUpgradableGuard<'a, T, R> { }
+
+impl<'a, T: ?Sized, R> core::ops::Deref for SpinRwLockUpgradableGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for SpinRwLockUpgradableGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("SpinRwLockUpgradableGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, R> core::fmt::Display
+    for SpinRwLockUpgradableGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, R> Drop for SpinRwLockUpgradableGuard<'a, T, R> {
+    fn drop(&mut self) {
+        unsafe { self.lock.raw_upgradable_unlock(); }
+    }
+}
+
+pub struct SpinRwLockWriteGuard<'a, T: ?Sized + 'a, R: 'a = relax::Spin> {
+    lock: &'a SpinRwLock<T, R>,
+    poison: poison::Guard,
+}
+
+impl<'a, T: ?Sized, R> SpinRwLockWriteGuard<'a, T, R> {
+    pub fn downgrade(self) -> SpinRwLockReadGuard<'a, T, R> {
+        let lock = self.lock;
+        core::mem::forget(self);
+
+        unsafe { lock.raw_downgrade(READER); }
+
+        SpinRwLockReadGuard{ lock }
+    }
+
+    pub fn downgrade_to_upgradable(self) -> SpinRwLockUpgradableGuard<'a, T, R> {
+        let lock = self.lock;
+        core::mem::forget(self);
+
+        unsafe { lock.raw_downgrade(UPGRADABLE); }
+
+        SpinRwLockUpgradableGuard{ lock }
+    }
+}
+
+unsafe impl<'a, T: ?Sized + Sync, R> Sync for SpinRwLockWriteGuard<'a, T, R> { }
+
+impl<'a, T: ?Sized, R> core::ops::Deref for SpinRwLockWriteGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized, R> core::ops::DerefMut for SpinRwLockWriteGuard<'a, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        match unsafe { self.lock.data.get().as_mut() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for SpinRwLockWriteGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("SpinRwLockWriteGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, R> core::fmt::Display
+    for SpinRwLockWriteGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, R> Drop for SpinRwLockWriteGuard<'a, T, R> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+
+        unsafe { self.lock.raw_write_unlock(); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use {SpinRwLock, TryLockError};
+
+    #[cfg(feature = "std")]
+    use relax;
+
+    #[test]
+    fn readers_share() {
+        let lock = SpinRwLock::new(1);
+
+        let first = lock.read().unwrap();
+        let second = lock.try_read().unwrap();
+        assert_eq!(*first + *second, 2);
+
+        match lock.try_write() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        drop(first);
+        drop(second);
+        assert!(lock.try_write().is_ok());
+    }
+
+    #[test]
+    fn writer_excludes() {
+        let lock = SpinRwLock::new(1);
+
+        let mut guard = lock.write().unwrap();
+        *guard += 1;
+
+        match lock.try_read() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        match lock.try_write() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        drop(guard);
+        assert_eq!(*lock.read().unwrap(), 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn poisoned_by_writers_only() {
+        let lock = SpinRwLock::new(());
+
+        let result = std::panic::catch_unwind(|| {
+            let _guard = lock.read().unwrap();
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert!(!lock.is_poisoned());
+
+        let result = std::panic::catch_unwind(|| {
+            let _guard = lock.write().unwrap();
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+        assert!(lock.read().is_err());
+        assert!(lock.into_inner().is_err());
+    }
+
+    #[test]
+    fn upgradable_read_shares_with_readers() {
+        let lock = SpinRwLock::new(1);
+
+        let reader = lock.read().unwrap();
+        let upgradable = lock.upgradable_read().unwrap();
+        let second = lock.try_read().unwrap();
+        assert_eq!(*reader + *upgradable + *second, 3);
+
+        match lock.try_upgradable_read() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        match lock.try_write() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        let upgradable = match upgradable.try_upgrade() {
+            Err(upgradable) => upgradable,
+            Ok(_) => panic!("upgraded while a reader was still inside"),
+        };
+
+        drop(reader);
+        drop(second);
+
+        let mut writer = upgradable.try_upgrade().ok().unwrap();
+        *writer += 1;
+
+        assert!(lock.try_read().is_err());
+        drop(writer);
+        assert_eq!(*lock.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn downgrade_keeps_the_lock() {
+        let lock = SpinRwLock::new(1);
+
+        let mut writer = lock.write().unwrap();
+        *writer += 1;
+
+        let reader = writer.downgrade();
+        assert_eq!(*reader, 2);
+        assert!(lock.try_read().is_ok());
+        assert!(lock.try_write().is_err());
+        drop(reader);
+
+        let upgradable = lock.write().unwrap().downgrade_to_upgradable();
+        assert!(lock.try_upgradable_read().is_err());
+        assert!(lock.try_read().is_ok());
+
+        let reader = upgradable.downgrade();
+        assert!(lock.try_upgradable_read().is_ok());
+        drop(reader);
+
+        assert!(lock.try_write().is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn pending_upgrade_holds_off_readers() {
+        let lock = std::sync::Arc::new(SpinRwLock::new(0));
+        let reader = lock.read().unwrap();
+
+        let upgrader = {
+            let lock = lock.clone();
+
+            std::thread::spawn(move || {
+                *lock.upgradable_read().unwrap().upgrade() += 1;
+            })
+        };
+
+        while lock.try_read().is_ok() {
+            std::thread::yield_now();
+        }
+
+        drop(reader);
+        upgrader.join().unwrap();
+
+        assert_eq!(*lock.try_read().unwrap(), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn upgrade_under_steady_reads() {
+        static NUM_READERS: usize = 4;
+
+        let lock = std::sync::Arc::new(
+            SpinRwLock::<_, relax::SpinThenYield>::with_relax(0)
+        );
+        let started =
+            std::sync::Arc::new(std::sync::Barrier::new(NUM_READERS + 1));
+        let done =
+            std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        // readers yield while inside, so there's nearly always one there
+        let readers: std::vec::Vec<_> = (0..NUM_READERS).map(|_| {
+            let lock = lock.clone();
+            let started = started.clone();
+            let done = done.clone();
+
+            std::thread::spawn(move || {
+                let reader = lock.read().unwrap();
+                started.wait();
+                std::thread::yield_now();
+                drop(reader);
+
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    let _reader = lock.read().unwrap();
+                    std::thread::yield_now();
+                }
+            })
+        }).collect();
+
+        started.wait();
+
+        for _ in 0..100 {
+            *lock.upgradable_read().unwrap().upgrade() += 1;
+        }
+
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(*lock.read().unwrap(), 100);
+    }
+}
@@ -0,0 +1,350 @@
+use poison;
+use relax;
+use sync;
+use AtomicFlag;
+
+// a sequence lock for small `Copy` data. writers serialize on a spin flag and
+// make the sequence number odd for as long as they hold the lock; readers
+// copy the data out and retry if the sequence number was odd or changed
+// underneath them, so a read never writes to shared memory.
+//
+// the data has to be `Copy`, since every read hands out a copy while the lock
+// keeps the original. a copy can still be torn by a writer, so it is made
+// into a `MaybeUninit` and only becomes a `T` once the sequence number shows
+// that no writer got in. poisoning follows Spinlock, with readers seeing the
+// poison too
+pub struct SeqLock<T: Copy, R = relax::Spin> {
+    is_locked: sync::atomic::AtomicBool,
+    seq: sync::atomic::AtomicUsize,
+    poison: poison::Flag,
+    relax: core::marker::PhantomData<R>,
+    data: core::cell::UnsafeCell<T>,
+}
+
+impl<T: Copy> SeqLock<T> {
+    loom_const_fn! {
+        pub fn new(t: T) -> SeqLock<T> {
+            SeqLock::with_relax(t)
+        }
+    }
+}
+
+impl<T: Copy, R> SeqLock<T, R> {
+    loom_const_fn! {
+        pub fn with_relax(t: T) -> SeqLock<T, R> {
+            SeqLock {
+                is_locked: sync::atomic::AtomicBool::new(false),
+                seq: sync::atomic::AtomicUsize::new(0),
+                poison: poison::Flag::new(),
+                relax: core::marker::PhantomData,
+                data: core::cell::UnsafeCell::new(t),
+            }
+        }
+    }
+
+    pub fn read(&self) -> poison::LockResult<T> where R: relax::Relax {
+        let mut relax = R::default();
+
+        loop {
+            if let Some(data) = unsafe { self.raw_try_read() } {
+                return self.poison.result(data);
+            }
+
+            relax.relax();
+        }
+    }
+
+    pub fn try_read(&self) -> poison::TryLockResult<T> {
+        match unsafe { self.raw_try_read() } {
+            Some(data) => Ok(self.poison.result(data)?),
+            None => Err(poison::TryLockError::WouldBlock),
+        }
+    }
+
+    pub fn write(&self) -> poison::LockResult<SeqLockWriteGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        unsafe { self.raw_write(); }
+
+        self.poison.result(SeqLockWriteGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })
+    }
+
+    pub fn try_write(&self) -> poison::TryLockResult<SeqLockWriteGuard<'_, T, R>> {
+        if unsafe { !self.raw_try_write() } {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(SeqLockWriteGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.poison.info()
+    }
+
+    pub fn into_inner(self) -> poison::LockResult<T> {
+        let SeqLock { poison, data, .. } = self;
+
+        poison.result(data.into_inner())
+    }
+
+    pub fn get_mut(&mut self) -> poison::LockResult<&mut T> {
+        let data = unsafe { &mut *self.data.get() };
+
+        self.poison.result(data)
+    }
+
+    // the copy may race with a writer; it is only assumed to be a valid `T`
+    // if the sequence number shows that no writer was inside while it was
+    // made
+    unsafe fn raw_try_read(&self) -> Option<T> {
+        let seq = self.seq.load(sync::atomic::Ordering::Acquire);
+
+        if seq & 1 != 0 {
+            return None;
+        }
+
+        let data = core::ptr::read_volatile(
+            self.data.get() as *const core::mem::MaybeUninit<T>
+        );
+        sync::atomic::fence(sync::atomic::Ordering::Acquire);
+
+        if self.seq.load(sync::atomic::Ordering::Relaxed) != seq {
+            return None;
+        }
+
+        Some(data.assume_init())
+    }
+
+    unsafe fn raw_write(&self) where R: relax::Relax {
+        let mut relax = R::default();
+
+        while !self.raw_try_write() {
+            while self.is_locked.test(sync::atomic::Ordering::Relaxed) {
+                relax.relax();
+            }
+        }
+    }
+
+    // the fence keeps the odd sequence number ahead of our writes to the data
+    unsafe fn raw_try_write(&self) -> bool {
+        if self.is_locked.test_and_set(sync::atomic::Ordering::Acquire) {
+            return false;
+        }
+
+        self.seq.fetch_add(1, sync::atomic::Ordering::Relaxed);
+        sync::atomic::fence(sync::atomic::Ordering::Release);
+
+        true
+    }
+
+    unsafe fn raw_write_unlock(&self) {
+        self.seq.fetch_add(1, sync::atomic::Ordering::Release);
+        self.is_locked.clear(sync::atomic::Ordering::Release);
+    }
+}
+
+impl<T: Copy, R> core::panic::UnwindSafe for SeqLock<T, R> { }
+
+impl<T: Copy, R> core::panic::RefUnwindSafe for SeqLock<T, R> { }
+
+unsafe impl<T: Copy + Send, R> Send for SeqLock<T, R> { }
+
+unsafe impl<T: Copy + Send, R> Sync for SeqLock<T, R> { }
+
+impl<T: Copy, R> From<T> for SeqLock<T, R> {
+    fn from(t: T) -> SeqLock<T, R> {
+        SeqLock::with_relax(t)
+    }
+}
+
+impl<T: Copy + Default, R> Default for SeqLock<T, R> {
+    fn default() -> SeqLock<T, R> {
+        SeqLock::with_relax(T::default())
+    }
+}
+
+impl<T: Copy + core::fmt::Debug, R> core::fmt::Debug for SeqLock<T, R> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.try_read() {
+            Ok(data) => f.debug_struct("SeqLock")
+                .field("data", &data)
+                .finish(),
+            Err(poison::TryLockError::Poisoned(err)) => {
+                f.debug_struct("SeqLock")
+                    .field("data", err.get_ref())
+                    .finish()
+            },
+            Err(poison::TryLockError::WouldBlock) => {
+                struct LockedPlaceholder;
+
+                impl core::fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self,
+                           f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                f.debug_struct("SeqLock")
+                    .field("data", &LockedPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+pub struct SeqLockWriteGuard<'a, T: Copy + 'a, R: 'a = relax::Spin> {
+    lock: &'a SeqLock<T, R>,
+    poison: poison::Guard,
+}
+
+unsafe impl<'a, T: Copy + Sync, R> Sync for SeqLockWriteGuard<'a, T, R> { }
+
+impl<'a, T: Copy, R> core::ops::Deref for SeqLockWriteGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: Copy, R> core::ops::DerefMut for SeqLockWriteGuard<'a, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        match unsafe { self.lock.data.get().as_mut() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: Copy + core::fmt::Debug, R> core::fmt::Debug
+    for SeqLockWriteGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("SeqLockWriteGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<'a, T: Copy + core::fmt::Display, R> core::fmt::Display
+    for SeqLockWriteGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: Copy, R> Drop for SeqLockWriteGuard<'a, T, R> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+
+        unsafe { self.lock.raw_write_unlock(); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use {SeqLock, TryLockError};
+
+    #[test]
+    fn writers_exclude() {
+        let lock = SeqLock::new(1);
+        assert_eq!(lock.read().unwrap(), 1);
+
+        let mut writer = lock.write().unwrap();
+        *writer += 1;
+
+        match lock.try_read() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        match lock.try_write() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        drop(writer);
+
+        assert_eq!(lock.try_read().unwrap(), 2);
+        assert_eq!(*lock.try_write().unwrap(), 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn poisoned() {
+        let lock = SeqLock::new(1);
+
+        let result = std::panic::catch_unwind(|| {
+            let mut guard = lock.write().unwrap();
+            *guard += 1;
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+
+        match lock.read() {
+            Err(err) => assert_eq!(err.into_inner(), 2),
+            Ok(_) => panic!("expected a poisoned read"),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn readers_never_see_torn_writes() {
+        static NUM_READERS: usize = 4;
+        static NUM_WRITES: usize = 1000;
+
+        let lock = std::sync::Arc::new(
+            SeqLock::<_, ::relax::SpinThenYield>::with_relax([0usize; 8])
+        );
+        let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let readers: std::vec::Vec<_> = (0..NUM_READERS).map(|_| {
+            let lock = lock.clone();
+            let done = done.clone();
+
+            std::thread::spawn(move || {
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    let data = lock.read().unwrap();
+                    assert!(data.iter().all(|&x| x == data[0]));
+                }
+            })
+        }).collect();
+
+        for i in 1..=NUM_WRITES {
+            let mut guard = lock.write().unwrap();
+
+            for x in guard.iter_mut() {
+                *x = i;
+                std::thread::yield_now();
+            }
+        }
+
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(lock.read().unwrap(), [NUM_WRITES; 8]);
+    }
+}
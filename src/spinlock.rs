@@ -1,163 +1,400 @@
-extern crate std;
-
-pub struct Spinlock<T: ?Sized> {
-    is_locked: std::sync::atomic::AtomicBool,
-    is_poisoned: std::sync::atomic::AtomicBool,
-    data: std::cell::UnsafeCell<T>,
+use cancel;
+use policy;
+use relax;
+use sync;
+
+#[cfg(any(feature = "track-owner", feature = "poison-info"))]
+use poison;
+#[cfg(feature = "track-owner")]
+use SeqLock;
+
+pub struct Spinlock<T: ?Sized, R = relax::Spin, P = policy::Poison>
+where
+    P: policy::Policy,
+{
+    is_locked: sync::atomic::AtomicBool,
+    // zero-sized unless `P` poisons
+    poison: P::Flag,
+    // only ever written by the thread holding the lock
+    #[cfg(feature = "track-owner")]
+    owner: SeqLock<Option<std::thread::ThreadId>>,
+    relax: core::marker::PhantomData<R>,
+    data: core::cell::UnsafeCell<T>,
 }
 
 impl<T> Spinlock<T> {
-    pub fn new(t: T) -> Spinlock<T> {
+    loom_const_fn! {
+        pub fn new(t: T) -> Spinlock<T> {
+            Spinlock::with_relax(t)
+        }
+    }
+}
+
+impl<T, R, P: policy::Policy> Spinlock<T, R, P> {
+    #[cfg(not(loom))]
+    pub const fn with_relax(t: T) -> Spinlock<T, R, P> {
+        Spinlock {
+            is_locked: sync::atomic::AtomicBool::new(false),
+            poison: P::FLAG_INIT,
+            #[cfg(feature = "track-owner")]
+            owner: SeqLock::new(None),
+            relax: core::marker::PhantomData,
+            data: core::cell::UnsafeCell::new(t),
+        }
+    }
+
+    #[cfg(loom)]
+    pub fn with_relax(t: T) -> Spinlock<T, R, P> {
         Spinlock {
-            is_locked: std::sync::atomic::ATOMIC_BOOL_INIT,
-            is_poisoned: std::sync::atomic::ATOMIC_BOOL_INIT,
-            data: std::cell::UnsafeCell::new(t),
+            is_locked: sync::atomic::AtomicBool::new(false),
+            poison: P::flag_init(),
+            #[cfg(feature = "track-owner")]
+            owner: SeqLock::new(None),
+            relax: core::marker::PhantomData,
+            data: core::cell::UnsafeCell::new(t),
         }
     }
 }
 
-impl <T: ?Sized> Spinlock<T> {
-    pub fn lock(&self) -> std::sync::LockResult<SpinlockGuard<T>> {
+// an unlocked, unpoisoned lock for places that need a constant rather than
+// a call, such as `[SPINLOCK_INIT; N]`. like the old `ATOMIC_BOOL_INIT`,
+// every use of it is a separate lock
+#[cfg(not(loom))]
+#[allow(clippy::declare_interior_mutable_const)]
+pub const SPINLOCK_INIT: Spinlock<()> = Spinlock::new(());
+
+impl<T: ?Sized, R, P: policy::Policy> Spinlock<T, R, P> {
+    pub fn lock(&self) -> P::LockResult<SpinlockGuard<'_, T, R, P>>
+    where
+        R: relax::Relax,
+    {
         unsafe { self.raw_lock(); }
 
-        let to_return = SpinlockGuard{ spinlock: self };
+        P::result(&self.poison, self.guard())
+    }
 
-        if self.is_poisoned() {
-            return Err(std::sync::PoisonError::new(to_return));
-        }
+    pub fn try_lock(&self) -> P::TryLockResult<SpinlockGuard<'_, T, R, P>> {
+        let guard = if unsafe { self.raw_try_lock() } {
+            Some(self.guard())
+        } else {
+            None
+        };
 
-        Ok(to_return)
+        P::try_result(&self.poison, guard)
     }
 
-    pub fn try_lock(&self) -> std::sync::TryLockResult<SpinlockGuard<T>> {
-        if unsafe { !self.raw_try_lock() } {
-            return Err(std::sync::TryLockError::WouldBlock);
+    #[cfg(feature = "std")]
+    pub fn try_lock_for(
+        &self,
+        timeout: std::time::Duration
+    ) -> P::TryLockResult<SpinlockGuard<'_, T, R, P>>
+    where
+        R: relax::Relax,
+    {
+        match std::time::Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            None => self.lock_unless(|| false),
         }
+    }
 
-        let to_return = SpinlockGuard{ spinlock: self };
+    #[cfg(feature = "std")]
+    pub fn try_lock_until(
+        &self,
+        deadline: std::time::Instant
+    ) -> P::TryLockResult<SpinlockGuard<'_, T, R, P>>
+    where
+        R: relax::Relax,
+    {
+        self.lock_unless(|| std::time::Instant::now() >= deadline)
+    }
 
-        if self.is_poisoned() {
-            let error = std::sync::PoisonError::new(to_return);
+    // gives up after relaxing `max_spins` times while the lock is held
+    pub fn lock_bounded(
+        &self,
+        max_spins: usize
+    ) -> P::TryLockResult<SpinlockGuard<'_, T, R, P>>
+    where
+        R: relax::Relax,
+    {
+        let mut spins = 0;
+
+        self.lock_unless(|| {
+            if spins == max_spins {
+                return true;
+            }
 
-            return Err(std::sync::TryLockError::Poisoned(error));
-        }
+            spins += 1;
 
-        Ok(to_return)
+            false
+        })
+    }
+
+    // fails without waiting once the token has been cancelled, and stops
+    // waiting as soon as it is
+    pub fn lock_cancellable(
+        &self,
+        token: &cancel::CancelToken
+    ) -> P::CancelLockResult<SpinlockGuard<'_, T, R, P>>
+    where
+        R: relax::Relax,
+    {
+        let acquired = !token.is_cancelled()
+            && unsafe { self.raw_lock_unless(|| token.is_cancelled()) };
+        let guard = if acquired { Some(self.guard()) } else { None };
+
+        P::cancel_result(&self.poison, guard)
     }
 
     pub fn is_poisoned(&self) -> bool {
-        self.is_poisoned.load(std::sync::atomic::Ordering::SeqCst)
+        P::is_poisoned(&self.poison)
     }
 
-    pub fn into_inner(self) -> std::sync::LockResult<T> where T: Sized {
-        unsafe {
-            let (_, poison, data) = {
-                let Spinlock {
-                    ref is_locked,
-                    ref is_poisoned,
-                    ref data,
-                } = self;
+    #[cfg(feature = "track-owner")]
+    pub fn owner(&self) -> Option<std::thread::ThreadId> {
+        self.owner.read().unwrap_or_else(poison::PoisonError::into_inner)
+    }
 
-                (
-                    std::ptr::read(is_locked),
-                    std::ptr::read(is_poisoned),
-                    std::ptr::read(data),
-                )
-            };
+    pub fn into_inner(self) -> P::LockResult<T> where T: Sized {
+        let Spinlock { poison, data, .. } = self;
 
-            std::mem::forget(self);
+        P::result(&poison, data.into_inner())
+    }
 
-            let inner = data.into_inner();
+    pub fn get_mut(&mut self) -> P::LockResult<&mut T> {
+        let data = unsafe { &mut *self.data.get() };
 
-            if poison.load(std::sync::atomic::Ordering::SeqCst) {
-                Err(std::sync::PoisonError::new(inner))
-            } else {
-                Ok(inner)
-            }
-        }
+        P::result(&self.poison, data)
     }
 
-    pub fn get_mut(&mut self) -> std::sync::LockResult<&mut T> {
-        let data = unsafe { &mut *self.data.get() };
+    // only call this once the lock is held
+    fn guard(&self) -> SpinlockGuard<'_, T, R, P> {
+        SpinlockGuard{ spinlock: self, poison: P::guard(&self.poison) }
+    }
 
-        if self.is_poisoned() {
-            Err(std::sync::PoisonError::new(data))
+    fn lock_unless<F>(
+        &self,
+        give_up: F
+    ) -> P::TryLockResult<SpinlockGuard<'_, T, R, P>>
+    where
+        F: FnMut() -> bool,
+        R: relax::Relax,
+    {
+        let guard = if unsafe { self.raw_lock_unless(give_up) } {
+            Some(self.guard())
         } else {
-            Ok(data)
+            None
+        };
+
+        P::try_result(&self.poison, guard)
+    }
+
+    // never giving up, this only fails on a relock that track-owner caught
+    pub(crate) unsafe fn raw_lock(&self) where R: relax::Relax {
+        if !self.raw_lock_unless(|| false) {
+            panic!("Spinlock locked again by the thread that holds it");
         }
     }
 
-    unsafe fn raw_lock(&self) {
-        while !self.raw_try_lock() { }
+    // asks `give_up` before each relax whether to stop waiting. with
+    // track-owner, gives up straight away on a lock this thread holds, since
+    // waiting for it could only end that way
+    unsafe fn raw_lock_unless<F>(&self, mut give_up: F) -> bool
+    where
+        F: FnMut() -> bool,
+        R: relax::Relax,
+    {
+        #[cfg(feature = "track-owner")]
+        {
+            if self.owner() == Some(std::thread::current().id()) {
+                return false;
+            }
+        }
+
+        let mut relax = R::default();
+
+        while !self.raw_try_lock() {
+            while self.is_locked.test(sync::atomic::Ordering::Relaxed) {
+                if give_up() {
+                    return false;
+                }
+
+                relax.relax();
+            }
+        }
+
+        true
+    }
+
+    pub(crate) unsafe fn raw_try_lock(&self) -> bool {
+        if self.is_locked.test_and_set(sync::atomic::Ordering::Acquire) {
+            return false;
+        }
+
+        #[cfg(feature = "track-owner")]
+        self.set_owner(Some(std::thread::current().id()));
+
+        true
     }
 
-    unsafe fn raw_try_lock(&self) -> bool {
-        !self.is_locked.test_and_set(std::sync::atomic::Ordering::SeqCst)
+    pub(crate) unsafe fn raw_unlock(&self) {
+        #[cfg(feature = "track-owner")]
+        self.set_owner(None);
+
+        self.is_locked.clear(sync::atomic::Ordering::Release);
     }
 
-    unsafe fn raw_unlock(&self) {
-        self.is_locked.clear(std::sync::atomic::Ordering::SeqCst);
+    // the guard is dropped while unwinding if the critical section panics,
+    // which poisons `owner` without leaving it half-written
+    #[cfg(feature = "track-owner")]
+    fn set_owner(&self, owner: Option<std::thread::ThreadId>) {
+        *self.owner.write().unwrap_or_else(poison::PoisonError::into_inner) =
+            owner;
     }
 }
 
-impl<T: ?Sized> std::panic::UnwindSafe for Spinlock<T> { }
+// recovering from poison only makes sense for a lock that can be poisoned
+impl<T: ?Sized, R> Spinlock<T, R> {
+    // if the lock is poisoned, `repair` gets to fix up the data before the
+    // poison is cleared. it runs under the lock, so only the first thread to
+    // find the lock poisoned repairs it; if `repair` panics, the lock stays
+    // poisoned for the next one to try
+    pub fn lock_or_recover<F>(&self, repair: F) -> SpinlockGuard<'_, T, R>
+    where
+        F: FnOnce(&mut T),
+        R: relax::Relax,
+    {
+        let mut guard = match self.lock() {
+            Ok(guard) => return guard,
+            Err(err) => err.into_inner(),
+        };
+
+        repair(&mut *guard);
+        self.poison.clear();
+
+        guard
+    }
 
-impl<T: ?Sized> std::panic::RefUnwindSafe for Spinlock<T> { }
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
 
-unsafe impl<T: ?Sized + Send> Send for Spinlock<T> { }
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.poison.info()
+    }
 
-unsafe impl<T: ?Sized + Send> Sync for Spinlock<T> { }
+    // like `lock`, `try_lock` and `into_inner`, but a poisoned result also
+    // says what poisoned the lock. the info is read with the poison flag, so
+    // unlike a later `poison_info` it can't miss a `clear_poison` or a newer
+    // panic in between
+    #[cfg(feature = "poison-info")]
+    pub fn lock_with_info(
+        &self
+    ) -> poison::LockResultWithInfo<SpinlockGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        unsafe { self.raw_lock(); }
+
+        self.poison.result_with_info(self.guard())
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn try_lock_with_info(
+        &self
+    ) -> poison::TryLockResultWithInfo<SpinlockGuard<'_, T, R>> {
+        if unsafe { !self.raw_try_lock() } {
+            return Err(poison::TryLockErrorWithInfo::WouldBlock);
+        }
 
-impl<T> From<T> for Spinlock<T> {
-    fn from(t: T) -> Spinlock<T> {
-        Spinlock::new(t)
+        Ok(self.poison.result_with_info(self.guard())?)
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn into_inner_with_info(self) -> poison::LockResultWithInfo<T>
+    where
+        T: Sized,
+    {
+        let Spinlock { poison, data, .. } = self;
+
+        poison.result_with_info(data.into_inner())
     }
 }
 
-impl<T: ?Sized + Default> Default for Spinlock<T> {
-    fn default() -> Spinlock<T> {
-        Spinlock::new(T::default())
+impl<T: ?Sized, R, P: policy::Policy> core::panic::UnwindSafe
+    for Spinlock<T, R, P> { }
+
+impl<T: ?Sized, R, P: policy::Policy> core::panic::RefUnwindSafe
+    for Spinlock<T, R, P> { }
+
+unsafe impl<T: ?Sized + Send, R, P: policy::Policy> Send
+    for Spinlock<T, R, P> { }
+
+unsafe impl<T: ?Sized + Send, R, P: policy::Policy> Sync
+    for Spinlock<T, R, P> { }
+
+impl<T, R, P: policy::Policy> From<T> for Spinlock<T, R, P> {
+    fn from(t: T) -> Spinlock<T, R, P> {
+        Spinlock::with_relax(t)
     }
 }
 
-impl<T: ?Sized + std::fmt::Debug> std::fmt::Debug for Spinlock<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self.try_lock() {
-            Ok(guard) => f.debug_struct("Spinlock")
+impl<T: Default, R, P: policy::Policy> Default for Spinlock<T, R, P> {
+    fn default() -> Spinlock<T, R, P> {
+        Spinlock::with_relax(T::default())
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug, R, P: policy::Policy> core::fmt::Debug
+    for Spinlock<T, R, P>
+{
+    // looks at the data whether or not the lock is poisoned, which the
+    // policy's result types can't express in general
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        if unsafe { self.raw_try_lock() } {
+            let guard = self.guard();
+
+            return f.debug_struct("Spinlock")
                 .field("data", &&*guard)
-                .finish(),
-            Err(std::sync::TryLockError::Poisoned(err)) => {
-                f.debug_struct("Spinlock")
-                    .field("data", &&**err.get_ref())
-                    .finish()
-            },
-            Err(std::sync::TryLockError::WouldBlock) => {
-                struct LockedPlaceholder;
-
-                impl std::fmt::Debug for LockedPlaceholder {
-                    fn fmt(&self,
-                           f: &mut std::fmt::Formatter) -> std::fmt::Result {
-                        f.write_str("<locked>")
-                    }
-                }
+                .finish();
+        }
 
-                f.debug_struct("Spinlock")
-                    .field("data", &LockedPlaceholder)
-                    .finish()
+        struct LockedPlaceholder;
+
+        impl core::fmt::Debug for LockedPlaceholder {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                f.write_str("<locked>")
             }
         }
+
+        let mut debug = f.debug_struct("Spinlock");
+        debug.field("data", &LockedPlaceholder);
+
+        #[cfg(feature = "track-owner")]
+        debug.field("owner", &self.owner());
+
+        debug.finish()
     }
 }
 
-pub struct SpinlockGuard<'a, T: ?Sized + 'a> {
-    spinlock: &'a Spinlock<T>,
+pub struct SpinlockGuard<'a, T, R = relax::Spin, P = policy::Poison>
+where
+    T: ?Sized + 'a,
+    R: 'a,
+    P: policy::Policy + 'a,
+{
+    spinlock: &'a Spinlock<T, R, P>,
+    poison: P::Guard,
 }
 
-// impl<'a, T: ?Sized> !Send for SpinlockGuard<'a, T> { }
+// impl<'a, T: ?Sized, R, P> !Send for SpinlockGuard<'a, T, R, P> { }
 
-unsafe impl<'a, T: ?Sized + Sync> Sync for SpinlockGuard<'a, T> { }
+unsafe impl<'a, T: ?Sized + Sync, R, P: policy::Policy> Sync
+    for SpinlockGuard<'a, T, R, P> { }
 
-impl<'a, T: ?Sized> std::ops::Deref for SpinlockGuard<'a, T> {
+impl<'a, T: ?Sized, R, P: policy::Policy> core::ops::Deref
+    for SpinlockGuard<'a, T, R, P>
+{
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -168,7 +405,9 @@ impl<'a, T: ?Sized> std::ops::Deref for SpinlockGuard<'a, T> {
     }
 }
 
-impl<'a, T: ?Sized> std::ops::DerefMut for SpinlockGuard<'a, T> {
+impl<'a, T: ?Sized, R, P: policy::Policy> core::ops::DerefMut
+    for SpinlockGuard<'a, T, R, P>
+{
     fn deref_mut(&mut self) -> &mut T {
         match unsafe { self.spinlock.data.get().as_mut() } {
             Some(v) => v,
@@ -177,38 +416,71 @@ impl<'a, T: ?Sized> std::ops::DerefMut for SpinlockGuard<'a, T> {
     }
 }
 
-impl<'a, T: ?Sized + std::fmt::Debug> std::fmt::Debug for SpinlockGuard<'a, T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl<'a, T: ?Sized + core::fmt::Debug, R, P: policy::Policy> core::fmt::Debug
+    for SpinlockGuard<'a, T, R, P>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         f.debug_struct("SpinlockGuard")
             .field("spinlock", &self.spinlock)
             .finish()
     }
 }
 
-impl<'a, T: ?Sized + std::fmt::Display> std::fmt::Display for SpinlockGuard<'a, T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl<'a, T: ?Sized + core::fmt::Display, R, P: policy::Policy>
+    core::fmt::Display for SpinlockGuard<'a, T, R, P>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         (**self).fmt(f)
     }
 }
 
-impl<'a, T: ?Sized> Drop for SpinlockGuard<'a, T> {
+impl<'a, T: ?Sized, R, P: policy::Policy> Drop
+    for SpinlockGuard<'a, T, R, P>
+{
     fn drop(&mut self) {
-        if std::thread::panicking() {
-            self.spinlock.is_poisoned.store(
-                true,
-                std::sync::atomic::Ordering::SeqCst
-            );
-        }
+        P::done(&self.spinlock.poison, &self.poison);
 
         unsafe { self.spinlock.raw_unlock(); }
     }
 }
 
+pub trait AtomicFlag {
+    fn clear(&self, order: sync::atomic::Ordering);
+
+    fn test_and_set(&self, order: sync::atomic::Ordering) -> bool;
+
+    fn test(&self, order: sync::atomic::Ordering) -> bool;
+}
+
+impl AtomicFlag for sync::atomic::AtomicBool {
+    fn clear(&self, order: sync::atomic::Ordering) {
+        self.store(false, order);
+    }
+
+    // a compare_exchange rather than a swap: a failed attempt leaves the
+    // flag alone, which is also the only form loom can model to completion
+    fn test_and_set(&self, order: sync::atomic::Ordering) -> bool {
+        self.compare_exchange(
+            false,
+            true,
+            order,
+            sync::atomic::Ordering::Relaxed
+        ).is_err()
+    }
+
+    fn test(&self, order: sync::atomic::Ordering) -> bool {
+        self.load(order)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate std;
 
-    use spinlock::Spinlock;
+    use {policy, relax, Spinlock, TryLockError, SPINLOCK_INIT};
+
+    #[cfg(feature = "std")]
+    use {CancelLockError, CancelToken, Relax};
 
     #[test]
     fn already_locked() {
@@ -221,44 +493,352 @@ mod tests {
         let new_guard = spinlock.try_lock();
 
         match new_guard {
-            Err(std::sync::TryLockError::WouldBlock) => assert!(true),
-            _ => assert!(false),
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
         }
 
         assert!(!spinlock.is_poisoned());
     }
 
+    #[cfg(feature = "std")]
     #[test]
     fn poisoned() {
         let spinlock = Spinlock::new(());
         assert!(!spinlock.is_poisoned());
 
         let result = std::panic::catch_unwind(|| {
-            match spinlock.lock() {
-                Ok(_) => {
-                    panic!();
-                }
-                _ => (),
+            if let Ok(_guard) = spinlock.lock() {
+                panic!();
             }
         });
 
         assert!(result.is_err());
         assert!(spinlock.is_poisoned());
     }
-}
 
-trait AtomicFlag {
-    fn clear(&self, order: std::sync::atomic::Ordering);
+    #[cfg(feature = "track-owner")]
+    #[test]
+    fn relocking_panics() {
+        let spinlock = Spinlock::new(());
+        assert_eq!(spinlock.owner(), None);
 
-    fn test_and_set(&self, order: std::sync::atomic::Ordering) -> bool;
-}
+        let guard = spinlock.lock().unwrap();
+        assert_eq!(spinlock.owner(), Some(std::thread::current().id()));
 
-impl AtomicFlag for std::sync::atomic::AtomicBool {
-    fn clear(&self, order: std::sync::atomic::Ordering) {
-        self.store(false, order);
+        let result = std::panic::catch_unwind(|| {
+            let _ = spinlock.lock();
+        });
+
+        assert!(result.is_err());
+
+        // waiting that can give up does so at once, since it would never see
+        // us let go
+        match spinlock.lock_bounded(usize::MAX) {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        match spinlock.try_lock_for(std::time::Duration::MAX) {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        match spinlock.lock_cancellable(&CancelToken::new()) {
+            Err(CancelLockError::Cancelled) => (),
+            _ => panic!("expected Cancelled"),
+        }
+
+        assert_eq!(spinlock.owner(), Some(std::thread::current().id()));
+        assert!(std::format!("{:?}", spinlock).contains("ThreadId"));
+
+        drop(guard);
+        assert_eq!(spinlock.owner(), None);
+        assert!(spinlock.try_lock().is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn not_poisoned() {
+        let spinlock =
+            Spinlock::<_, relax::Spin, policy::NoPoison>::with_relax(0);
+
+        let result = std::panic::catch_unwind(|| {
+            *spinlock.lock() += 1;
+            let _guard = spinlock.lock_bounded(0);
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert!(!spinlock.is_poisoned());
+        assert_eq!(*spinlock.try_lock().unwrap(), 1);
+
+        let token = CancelToken::new();
+        assert!(spinlock.lock_cancellable(&token).is_some());
+        token.cancel();
+        assert!(spinlock.lock_cancellable(&token).is_none());
+
+        assert_eq!(spinlock.into_inner(), 1);
     }
 
-    fn test_and_set(&self, order: std::sync::atomic::Ordering) -> bool {
-        self.swap(true, order)
+    #[cfg(feature = "std")]
+    #[test]
+    fn abort_spares_guards_taken_while_unwinding() {
+        struct LocksOnDrop<'a>(&'a Spinlock<usize, relax::Spin, policy::Abort>);
+
+        impl<'a> Drop for LocksOnDrop<'a> {
+            fn drop(&mut self) {
+                *self.0.lock() += 1;
+            }
+        }
+
+        let spinlock = Spinlock::<_, relax::Spin, policy::Abort>::with_relax(0);
+
+        let result = std::panic::catch_unwind(|| {
+            let _locks_on_drop = LocksOnDrop(&spinlock);
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert_eq!(*spinlock.try_lock().unwrap(), 1);
+    }
+
+    #[cfg(not(feature = "track-owner"))]
+    #[test]
+    fn smaller_without_poison() {
+        assert!(
+            core::mem::size_of::<Spinlock<(), relax::Spin, policy::NoPoison>>()
+                < core::mem::size_of::<Spinlock<()>>()
+        );
+        assert_eq!(
+            core::mem::size_of::<Spinlock<(), relax::Spin, policy::Abort>>(),
+            core::mem::size_of::<Spinlock<(), relax::Spin, policy::NoPoison>>()
+        );
+    }
+
+    // the waiting happens on another thread, so that track-owner doesn't
+    // give up on a relock before spinning at all
+    #[test]
+    fn bounded_spinning() {
+        let spinlock = std::sync::Arc::new(Spinlock::new(()));
+        let guard = spinlock.lock_bounded(0).unwrap();
+
+        let cloned = spinlock.clone();
+        let waiter = std::thread::spawn(move || {
+            match cloned.lock_bounded(100) {
+                Err(TryLockError::WouldBlock) => (),
+                _ => panic!("expected WouldBlock"),
+            }
+        });
+
+        waiter.join().unwrap();
+
+        drop(guard);
+        assert!(spinlock.lock_bounded(0).is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn timeouts() {
+        let spinlock = std::sync::Arc::new(
+            Spinlock::<_, relax::SpinThenYield>::with_relax(())
+        );
+        let guard = spinlock.lock().unwrap();
+
+        let timeout = std::time::Duration::from_millis(10);
+
+        let cloned = spinlock.clone();
+        let waiter = std::thread::spawn(move || {
+            let start = std::time::Instant::now();
+
+            match cloned.try_lock_for(timeout) {
+                Err(TryLockError::WouldBlock) => (),
+                _ => panic!("expected WouldBlock"),
+            }
+
+            assert!(start.elapsed() >= timeout);
+
+            match cloned.try_lock_until(std::time::Instant::now()) {
+                Err(TryLockError::WouldBlock) => (),
+                _ => panic!("expected WouldBlock"),
+            }
+        });
+
+        waiter.join().unwrap();
+
+        let cloned = spinlock.clone();
+        let waiter = std::thread::spawn(move || {
+            cloned.try_lock_for(std::time::Duration::from_secs(60)).is_ok()
+        });
+
+        std::thread::sleep(timeout);
+        drop(guard);
+
+        assert!(waiter.join().unwrap());
+        assert!(spinlock.try_lock_for(std::time::Duration::MAX).is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn cancel_waiters() {
+        static NUM_WAITERS: usize = 4;
+
+        let spinlock = std::sync::Arc::new(
+            Spinlock::<_, relax::SpinThenYield>::with_relax(())
+        );
+        let token = std::sync::Arc::new(CancelToken::new());
+        let started =
+            std::sync::Arc::new(std::sync::Barrier::new(NUM_WAITERS + 1));
+        let guard = spinlock.lock().unwrap();
+
+        let waiters: std::vec::Vec<_> = (0..NUM_WAITERS).map(|_| {
+            let spinlock = spinlock.clone();
+            let token = token.clone();
+            let started = started.clone();
+
+            std::thread::spawn(move || {
+                started.wait();
+
+                match spinlock.lock_cancellable(&token) {
+                    Err(CancelLockError::Cancelled) => (),
+                    _ => panic!("expected Cancelled"),
+                }
+            })
+        }).collect();
+
+        started.wait();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        token.cancel();
+
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+
+        drop(guard);
+
+        match spinlock.lock_cancellable(&token) {
+            Err(CancelLockError::Cancelled) => (),
+            _ => panic!("expected Cancelled"),
+        }
+
+        assert!(spinlock.lock_cancellable(&CancelToken::new()).is_ok());
+    }
+
+    #[test]
+    fn usable_in_statics() {
+        static COUNT: Spinlock<usize> = Spinlock::new(0);
+        static LOCKS: [Spinlock<()>; 4] = [SPINLOCK_INIT; 4];
+
+        let guards: [_; 4] = core::array::from_fn(|i| LOCKS[i].lock().unwrap());
+        assert!(LOCKS.iter().all(|lock| lock.try_lock().is_err()));
+        drop(guards);
+
+        *COUNT.lock().unwrap() += 1;
+        assert_eq!(*COUNT.try_lock().unwrap(), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn contended_static() {
+        static NUM_THREADS: usize = 8;
+        static NUM_ITERS: usize = 1000;
+        static LOG: Spinlock<std::vec::Vec<usize>> =
+            Spinlock::new(std::vec::Vec::new());
+
+        let threads: std::vec::Vec<_> = (0..NUM_THREADS).map(|i| {
+            std::thread::spawn(move || {
+                for _ in 0..NUM_ITERS {
+                    LOG.lock().unwrap().push(i);
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let log = LOG.lock().unwrap();
+        assert_eq!(log.len(), NUM_THREADS * NUM_ITERS);
+
+        for i in 0..NUM_THREADS {
+            assert_eq!(log.iter().filter(|&&j| j == i).count(), NUM_ITERS);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn recover_from_poison() {
+        let spinlock = Spinlock::new(std::vec::Vec::new());
+
+        let result = std::panic::catch_unwind(|| {
+            let mut guard = spinlock.lock().unwrap();
+            guard.push(1);
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert!(spinlock.is_poisoned());
+
+        let result = std::panic::catch_unwind(|| {
+            spinlock.lock_or_recover(|_| panic!());
+        });
+
+        assert!(result.is_err());
+        assert!(spinlock.is_poisoned());
+
+        let mut repairs = 0;
+        let mut guard = spinlock.lock_or_recover(|data| {
+            repairs += 1;
+            data.clear();
+        });
+
+        assert!(guard.is_empty());
+        guard.push(2);
+        drop(guard);
+
+        assert!(!spinlock.is_poisoned());
+        assert_eq!(*spinlock.lock_or_recover(|_| repairs += 1), [2]);
+        assert_eq!(repairs, 1);
+
+        let _ = std::panic::catch_unwind(|| {
+            let _guard = spinlock.lock().unwrap();
+            panic!();
+        });
+
+        assert!(spinlock.is_poisoned());
+        spinlock.clear_poison();
+        assert_eq!(*spinlock.lock().unwrap(), [2]);
+    }
+
+    #[cfg(feature = "std")]
+    fn count_contended<R: Relax + 'static>() {
+        static NUM_THREADS: usize = 8;
+        static NUM_ITERS: usize = 1000;
+
+        let spinlock = std::sync::Arc::new(Spinlock::<usize, R>::with_relax(0));
+
+        let threads: std::vec::Vec<_> = (0..NUM_THREADS).map(|_| {
+            let cloned = spinlock.clone();
+
+            std::thread::spawn(move || {
+                for _ in 0..NUM_ITERS {
+                    *cloned.lock().unwrap() += 1;
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(*spinlock.lock().unwrap(), NUM_THREADS * NUM_ITERS);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn relax_strategies() {
+        count_contended::<relax::Spin>();
+        count_contended::<relax::Backoff>();
+        count_contended::<relax::SpinThenYield>();
+        count_contended::<relax::NoRelax>();
     }
 }
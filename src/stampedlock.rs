@@ -0,0 +1,573 @@
+use poison;
+use relax;
+use sync;
+
+// a reader-writer lock with a third, optimistic read mode in the style of
+// Java's StampedLock. an optimistic read takes a stamp without writing to the
+// lock at all, copies the data out, and then checks that no writer got in
+// since the stamp was taken; when that keeps failing, fall back to a shared
+// read.
+//
+// poisoning follows SpinRwLock: only a panicking writer poisons the lock
+pub struct StampedLock<T: ?Sized, R = relax::Spin> {
+    // the number of readers times `READER`, plus `WRITER` while write-locked.
+    // releasing a write adds `WRITER` again, which carries into the version
+    // in the bits above it, so every write invalidates outstanding stamps
+    state: sync::atomic::AtomicUsize,
+    poison: poison::Flag,
+    relax: core::marker::PhantomData<R>,
+    data: core::cell::UnsafeCell<T>,
+}
+
+const READER: usize = 1;
+const READERS: usize = 0x7fff;
+const WRITER: usize = READERS + 1;
+
+// the version of a StampedLock at the time it was taken. stamps are only
+// handed out while the lock isn't write-locked, so they never have the
+// `WRITER` bit or any readers set
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stamp(usize);
+
+impl<T> StampedLock<T> {
+    loom_const_fn! {
+        pub fn new(t: T) -> StampedLock<T> {
+            StampedLock::with_relax(t)
+        }
+    }
+}
+
+impl<T, R> StampedLock<T, R> {
+    loom_const_fn! {
+        pub fn with_relax(t: T) -> StampedLock<T, R> {
+            StampedLock {
+                state: sync::atomic::AtomicUsize::new(0),
+                poison: poison::Flag::new(),
+                relax: core::marker::PhantomData,
+                data: core::cell::UnsafeCell::new(t),
+            }
+        }
+    }
+
+    // copies the data out without locking, or returns `None` if a writer
+    // held the lock or got in while the copy was made. a torn copy is never
+    // assumed to be a valid `T`
+    pub fn optimistic_read(&self) -> Option<T> where T: Copy {
+        let stamp = self.try_optimistic_read()?;
+
+        let data = unsafe {
+            core::ptr::read_volatile(
+                self.data.get() as *const core::mem::MaybeUninit<T>
+            )
+        };
+
+        if !self.validate(stamp) {
+            return None;
+        }
+
+        Some(unsafe { data.assume_init() })
+    }
+}
+
+impl<T: ?Sized, R> StampedLock<T, R> {
+    pub fn try_optimistic_read(&self) -> Option<Stamp> {
+        let state = self.state.load(sync::atomic::Ordering::Acquire);
+
+        if state & WRITER != 0 {
+            return None;
+        }
+
+        Some(Stamp(state & !READERS))
+    }
+
+    pub fn validate(&self, stamp: Stamp) -> bool {
+        sync::atomic::fence(sync::atomic::Ordering::Acquire);
+
+        self.state.load(sync::atomic::Ordering::Relaxed) & !READERS == stamp.0
+    }
+
+    // write-locks only if nothing has happened since the stamp was taken and
+    // nobody holds the lock, so a stale stamp fails just like a held lock
+    pub fn try_convert_to_write(
+        &self,
+        stamp: Stamp
+    ) -> poison::TryLockResult<StampedLockWriteGuard<'_, T, R>> {
+        if self.state.compare_exchange(
+            stamp.0,
+            stamp.0 | WRITER,
+            sync::atomic::Ordering::Acquire,
+            sync::atomic::Ordering::Relaxed
+        ).is_err() {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        // see raw_try_write
+        sync::atomic::fence(sync::atomic::Ordering::Release);
+
+        Ok(self.poison.result(StampedLockWriteGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })?)
+    }
+
+    pub fn read(&self) -> poison::LockResult<StampedLockReadGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        unsafe { self.raw_read(); }
+
+        self.poison.result(StampedLockReadGuard{ lock: self })
+    }
+
+    pub fn try_read(&self) -> poison::TryLockResult<StampedLockReadGuard<'_, T, R>> {
+        if unsafe { !self.raw_try_read() } {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(StampedLockReadGuard{ lock: self })?)
+    }
+
+    pub fn write(&self) -> poison::LockResult<StampedLockWriteGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        unsafe { self.raw_write(); }
+
+        self.poison.result(StampedLockWriteGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })
+    }
+
+    pub fn try_write(
+        &self
+    ) -> poison::TryLockResult<StampedLockWriteGuard<'_, T, R>> {
+        if unsafe { !self.raw_try_write() } {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(StampedLockWriteGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.poison.info()
+    }
+
+    pub fn into_inner(self) -> poison::LockResult<T> where T: Sized {
+        let StampedLock { poison, data, .. } = self;
+
+        poison.result(data.into_inner())
+    }
+
+    pub fn get_mut(&mut self) -> poison::LockResult<&mut T> {
+        let data = unsafe { &mut *self.data.get() };
+
+        self.poison.result(data)
+    }
+
+    unsafe fn raw_read(&self) where R: relax::Relax {
+        let mut relax = R::default();
+
+        while !self.raw_try_read() {
+            while {
+                let state = self.state.load(sync::atomic::Ordering::Relaxed);
+
+                state & WRITER != 0 || state & READERS == READERS
+            } {
+                relax.relax();
+            }
+        }
+    }
+
+    // a full reader count makes further readers wait rather than overflow
+    // into the `WRITER` bit
+    unsafe fn raw_try_read(&self) -> bool {
+        let state = self.state.load(sync::atomic::Ordering::Relaxed);
+
+        state & WRITER == 0 && state & READERS != READERS
+            && self.state.compare_exchange(
+                state,
+                state + READER,
+                sync::atomic::Ordering::Acquire,
+                sync::atomic::Ordering::Relaxed
+            ).is_ok()
+    }
+
+    unsafe fn raw_read_unlock(&self) {
+        self.state.fetch_sub(READER, sync::atomic::Ordering::Release);
+    }
+
+    // the only reader can trade its read for a write; the version can't
+    // have moved while it held the read
+    unsafe fn raw_try_convert_read(&self) -> bool {
+        let state = self.state.load(sync::atomic::Ordering::Relaxed);
+
+        if state & READERS != READER || self.state.compare_exchange(
+            state,
+            (state - READER) | WRITER,
+            sync::atomic::Ordering::Acquire,
+            sync::atomic::Ordering::Relaxed
+        ).is_err() {
+            return false;
+        }
+
+        // see raw_try_write
+        sync::atomic::fence(sync::atomic::Ordering::Release);
+
+        true
+    }
+
+    unsafe fn raw_write(&self) where R: relax::Relax {
+        let mut relax = R::default();
+
+        while !self.raw_try_write() {
+            while self.state.load(sync::atomic::Ordering::Relaxed)
+                & (WRITER | READERS) != 0
+            {
+                relax.relax();
+            }
+        }
+    }
+
+    // the fence keeps the `WRITER` bit ahead of our writes to the data, so
+    // an optimistic reader that saw any of them fails to validate
+    unsafe fn raw_try_write(&self) -> bool {
+        let state = self.state.load(sync::atomic::Ordering::Relaxed);
+
+        if state & (WRITER | READERS) != 0 || self.state.compare_exchange(
+            state,
+            state | WRITER,
+            sync::atomic::Ordering::Acquire,
+            sync::atomic::Ordering::Relaxed
+        ).is_err() {
+            return false;
+        }
+
+        sync::atomic::fence(sync::atomic::Ordering::Release);
+
+        true
+    }
+
+    unsafe fn raw_write_unlock(&self) {
+        self.state.fetch_add(WRITER, sync::atomic::Ordering::Release);
+    }
+}
+
+impl<T: ?Sized, R> core::panic::UnwindSafe for StampedLock<T, R> { }
+
+impl<T: ?Sized, R> core::panic::RefUnwindSafe for StampedLock<T, R> { }
+
+unsafe impl<T: ?Sized + Send, R> Send for StampedLock<T, R> { }
+
+unsafe impl<T: ?Sized + Send + Sync, R> Sync for StampedLock<T, R> { }
+
+impl<T, R> From<T> for StampedLock<T, R> {
+    fn from(t: T) -> StampedLock<T, R> {
+        StampedLock::with_relax(t)
+    }
+}
+
+impl<T: Default, R> Default for StampedLock<T, R> {
+    fn default() -> StampedLock<T, R> {
+        StampedLock::with_relax(T::default())
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug, R> core::fmt::Debug for StampedLock<T, R> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.try_read() {
+            Ok(guard) => f.debug_struct("StampedLock")
+                .field("data", &&*guard)
+                .finish(),
+            Err(poison::TryLockError::Poisoned(err)) => {
+                f.debug_struct("StampedLock")
+                    .field("data", &&**err.get_ref())
+                    .finish()
+            },
+            Err(poison::TryLockError::WouldBlock) => {
+                struct LockedPlaceholder;
+
+                impl core::fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self,
+                           f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                f.debug_struct("StampedLock")
+                    .field("data", &LockedPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+pub struct StampedLockReadGuard<'a, T: ?Sized + 'a, R: 'a = relax::Spin> {
+    lock: &'a StampedLock<T, R>,
+}
+
+impl<'a, T: ?Sized, R> StampedLockReadGuard<'a, T, R> {
+    pub fn try_convert_to_write(
+        self
+    ) -> Result<StampedLockWriteGuard<'a, T, R>, Self> {
+        if unsafe { !self.lock.raw_try_convert_read() } {
+            return Err(self);
+        }
+
+        let lock = self.lock;
+        core::mem::forget(self);
+
+        Ok(StampedLockWriteGuard{ lock, poison: lock.poison.guard() })
+    }
+}
+
+unsafe impl<'a, T: ?Sized + Sync, R> Sync for StampedLockReadGuard<'a, T, R> { }
+
+impl<'a, T: ?Sized, R> core::ops::Deref for StampedLockReadGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for StampedLockReadGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("StampedLockReadGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, R> core::fmt::Display
+    for StampedLockReadGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, R> Drop for StampedLockReadGuard<'a, T, R> {
+    fn drop(&mut self) {
+        unsafe { self.lock.raw_read_unlock(); }
+    }
+}
+
+pub struct StampedLockWriteGuard<'a, T: ?Sized + 'a, R: 'a = relax::Spin> {
+    lock: &'a StampedLock<T, R>,
+    poison: poison::Guard,
+}
+
+impl<'a, T: ?Sized, R> StampedLockWriteGuard<'a, T, R> {
+    // releasing the write bumps the version, so stamps taken before this
+    // write still fail to validate
+    pub fn downgrade(self) -> StampedLockReadGuard<'a, T, R> {
+        let lock = self.lock;
+        core::mem::forget(self);
+
+        lock.state.fetch_add(WRITER + READER, sync::atomic::Ordering::Release);
+
+        StampedLockReadGuard{ lock }
+    }
+}
+
+unsafe impl<'a, T: ?Sized + Sync, R> Sync for StampedLockWriteGuard<'a, T, R> { }
+
+impl<'a, T: ?Sized, R> core::ops::Deref for StampedLockWriteGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized, R> core::ops::DerefMut for StampedLockWriteGuard<'a, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        match unsafe { self.lock.data.get().as_mut() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for StampedLockWriteGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("StampedLockWriteGuard")
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, R> core::fmt::Display
+    for StampedLockWriteGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, R> Drop for StampedLockWriteGuard<'a, T, R> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+
+        unsafe { self.lock.raw_write_unlock(); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use {StampedLock, TryLockError};
+
+    #[test]
+    fn only_writes_invalidate_stamps() {
+        let lock = StampedLock::new(1);
+        let stamp = lock.try_optimistic_read().unwrap();
+        assert_eq!(lock.optimistic_read(), Some(1));
+
+        let first = lock.read().unwrap();
+        let second = lock.try_read().unwrap();
+        assert_eq!(lock.try_optimistic_read(), Some(stamp));
+        assert!(lock.validate(stamp));
+
+        match lock.try_write() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        drop(first);
+        drop(second);
+
+        let mut writer = lock.write().unwrap();
+        *writer += 1;
+        assert!(lock.try_optimistic_read().is_none());
+        assert!(lock.optimistic_read().is_none());
+        assert!(!lock.validate(stamp));
+        drop(writer);
+
+        assert!(!lock.validate(stamp));
+        assert!(lock.validate(lock.try_optimistic_read().unwrap()));
+        assert_eq!(*lock.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn conversions() {
+        let lock = StampedLock::new(1);
+
+        let stale = lock.try_optimistic_read().unwrap();
+        *lock.write().unwrap() += 1;
+        assert!(lock.try_convert_to_write(stale).is_err());
+
+        let stamp = lock.try_optimistic_read().unwrap();
+        let mut writer = lock.try_convert_to_write(stamp).unwrap();
+        *writer += 1;
+
+        let reader = writer.downgrade();
+        assert_eq!(*reader, 3);
+        assert!(!lock.validate(stamp));
+
+        let other = lock.read().unwrap();
+        let reader = match reader.try_convert_to_write() {
+            Err(reader) => reader,
+            Ok(_) => panic!("converted while another reader was inside"),
+        };
+
+        drop(other);
+
+        let mut writer = reader.try_convert_to_write().ok().unwrap();
+        *writer += 1;
+        assert!(lock.try_read().is_err());
+        drop(writer);
+
+        assert_eq!(*lock.read().unwrap(), 4);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn poisoned_by_writers_only() {
+        let lock = StampedLock::new(());
+
+        let result = std::panic::catch_unwind(|| {
+            let _guard = lock.read().unwrap();
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert!(!lock.is_poisoned());
+
+        let result = std::panic::catch_unwind(|| {
+            let _guard = lock.write().unwrap();
+            panic!();
+        });
+
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+        assert!(lock.read().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn optimistic_reads_fall_back_to_shared() {
+        static NUM_READERS: usize = 4;
+        static NUM_WRITES: usize = 1000;
+
+        let lock = std::sync::Arc::new(
+            StampedLock::<_, ::relax::SpinThenYield>::with_relax((0usize, 0usize))
+        );
+        let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let readers: std::vec::Vec<_> = (0..NUM_READERS).map(|_| {
+            let lock = lock.clone();
+            let done = done.clone();
+
+            std::thread::spawn(move || {
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    let optimistic = (0..4)
+                        .filter_map(|_| lock.optimistic_read())
+                        .next();
+
+                    let (first, second) = match optimistic {
+                        Some(data) => data,
+                        None => *lock.read().unwrap(),
+                    };
+
+                    assert_eq!(first, second);
+                }
+            })
+        }).collect();
+
+        for i in 1..=NUM_WRITES {
+            let mut guard = lock.write().unwrap();
+            guard.0 = i;
+            std::thread::yield_now();
+            guard.1 = i;
+        }
+
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(*lock.read().unwrap(), (NUM_WRITES, NUM_WRITES));
+    }
+}
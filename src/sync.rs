@@ -0,0 +1,24 @@
+// loom needs to see every atomic access and every spin to explore the
+// interleavings of a model, so route both through here
+
+#[cfg(not(loom))]
+pub mod atomic {
+    #[allow(unused_imports)]
+    pub use core::sync::atomic::{
+        fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering
+    };
+}
+
+#[cfg(loom)]
+pub mod atomic {
+    #[allow(unused_imports)]
+    pub use loom::sync::atomic::{
+        fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering
+    };
+}
+
+#[cfg(not(loom))]
+pub use core::hint::spin_loop;
+
+#[cfg(loom)]
+pub use loom::hint::spin_loop;
@@ -0,0 +1,289 @@
+use poison;
+use relax;
+use sync;
+
+// waiters are served in the order they took a ticket
+pub struct TicketLock<T: ?Sized, R = relax::Spin> {
+    next_ticket: sync::atomic::AtomicUsize,
+    now_serving: sync::atomic::AtomicUsize,
+    poison: poison::Flag,
+    relax: core::marker::PhantomData<R>,
+    data: core::cell::UnsafeCell<T>,
+}
+
+impl<T> TicketLock<T> {
+    loom_const_fn! {
+        pub fn new(t: T) -> TicketLock<T> {
+            TicketLock::with_relax(t)
+        }
+    }
+}
+
+impl<T, R> TicketLock<T, R> {
+    loom_const_fn! {
+        pub fn with_relax(t: T) -> TicketLock<T, R> {
+            TicketLock {
+                next_ticket: sync::atomic::AtomicUsize::new(0),
+                now_serving: sync::atomic::AtomicUsize::new(0),
+                poison: poison::Flag::new(),
+                relax: core::marker::PhantomData,
+                data: core::cell::UnsafeCell::new(t),
+            }
+        }
+    }
+}
+
+impl<T: ?Sized, R> TicketLock<T, R> {
+    pub fn lock(&self) -> poison::LockResult<TicketLockGuard<'_, T, R>>
+    where
+        R: relax::Relax,
+    {
+        unsafe { self.raw_lock(); }
+
+        self.poison.result(TicketLockGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })
+    }
+
+    pub fn try_lock(&self) -> poison::TryLockResult<TicketLockGuard<'_, T, R>> {
+        if unsafe { !self.raw_try_lock() } {
+            return Err(poison::TryLockError::WouldBlock);
+        }
+
+        Ok(self.poison.result(TicketLockGuard{
+            lock: self,
+            poison: self.poison.guard(),
+        })?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    #[cfg(feature = "poison-info")]
+    pub fn poison_info(&self) -> Option<poison::PoisonInfo> {
+        self.poison.info()
+    }
+
+    pub fn into_inner(self) -> poison::LockResult<T> where T: Sized {
+        let TicketLock { poison, data, .. } = self;
+
+        poison.result(data.into_inner())
+    }
+
+    pub fn get_mut(&mut self) -> poison::LockResult<&mut T> {
+        let data = unsafe { &mut *self.data.get() };
+
+        self.poison.result(data)
+    }
+
+    unsafe fn raw_lock(&self) where R: relax::Relax {
+        let ticket = self.next_ticket.fetch_add(
+            1,
+            sync::atomic::Ordering::Relaxed
+        );
+        let mut relax = R::default();
+
+        while self.now_serving.load(sync::atomic::Ordering::Acquire) != ticket {
+            relax.relax();
+        }
+    }
+
+    // only succeeds if nobody holds or is waiting for the lock, so it never
+    // jumps the queue
+    unsafe fn raw_try_lock(&self) -> bool {
+        let serving = self.now_serving.load(sync::atomic::Ordering::Acquire);
+
+        self.next_ticket.compare_exchange(
+            serving,
+            serving.wrapping_add(1),
+            sync::atomic::Ordering::Relaxed,
+            sync::atomic::Ordering::Relaxed
+        ).is_ok()
+    }
+
+    unsafe fn raw_unlock(&self) {
+        self.now_serving.fetch_add(1, sync::atomic::Ordering::Release);
+    }
+}
+
+impl<T: ?Sized, R> core::panic::UnwindSafe for TicketLock<T, R> { }
+
+impl<T: ?Sized, R> core::panic::RefUnwindSafe for TicketLock<T, R> { }
+
+unsafe impl<T: ?Sized + Send, R> Send for TicketLock<T, R> { }
+
+unsafe impl<T: ?Sized + Send, R> Sync for TicketLock<T, R> { }
+
+impl<T, R> From<T> for TicketLock<T, R> {
+    fn from(t: T) -> TicketLock<T, R> {
+        TicketLock::with_relax(t)
+    }
+}
+
+impl<T: Default, R> Default for TicketLock<T, R> {
+    fn default() -> TicketLock<T, R> {
+        TicketLock::with_relax(T::default())
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for TicketLock<T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.try_lock() {
+            Ok(guard) => f.debug_struct("TicketLock")
+                .field("data", &&*guard)
+                .finish(),
+            Err(poison::TryLockError::Poisoned(err)) => {
+                f.debug_struct("TicketLock")
+                    .field("data", &&**err.get_ref())
+                    .finish()
+            },
+            Err(poison::TryLockError::WouldBlock) => {
+                struct LockedPlaceholder;
+
+                impl core::fmt::Debug for LockedPlaceholder {
+                    fn fmt(&self,
+                           f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        f.write_str("<locked>")
+                    }
+                }
+
+                f.debug_struct("TicketLock")
+                    .field("data", &LockedPlaceholder)
+                    .finish()
+            }
+        }
+    }
+}
+
+pub struct TicketLockGuard<'a, T: ?Sized + 'a, R: 'a = relax::Spin> {
+    lock: &'a TicketLock<T, R>,
+    poison: poison::Guard,
+}
+
+unsafe impl<'a, T: ?Sized + Sync, R> Sync for TicketLockGuard<'a, T, R> { }
+
+impl<'a, T: ?Sized, R> core::ops::Deref for TicketLockGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match unsafe { self.lock.data.get().as_ref() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized, R> core::ops::DerefMut for TicketLockGuard<'a, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        match unsafe { self.lock.data.get().as_mut() } {
+            Some(v) => v,
+            None => panic!("data ptr is null"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Debug, R> core::fmt::Debug
+    for TicketLockGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("TicketLockGuard")
+            .field("lock", &self.lock)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + core::fmt::Display, R> core::fmt::Display
+    for TicketLockGuard<'a, T, R>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized, R> Drop for TicketLockGuard<'a, T, R> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+
+        unsafe { self.lock.raw_unlock(); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use {TicketLock, TryLockError};
+
+    #[test]
+    fn already_locked() {
+        let lock = TicketLock::new(());
+        assert!(!lock.is_poisoned());
+
+        let guard = lock.lock();
+        assert!(guard.is_ok());
+
+        match lock.try_lock() {
+            Err(TryLockError::WouldBlock) => (),
+            _ => panic!("expected WouldBlock"),
+        }
+
+        drop(guard);
+        assert!(lock.try_lock().is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn poisoned() {
+        let lock = TicketLock::new(());
+        assert!(!lock.is_poisoned());
+
+        let result = std::panic::catch_unwind(|| {
+            if let Ok(_guard) = lock.lock() {
+                panic!();
+            }
+        });
+
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+        assert!(lock.into_inner().is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn served_in_ticket_order() {
+        let lock = std::sync::Arc::new(TicketLock::new(std::vec::Vec::new()));
+        let guard = lock.lock().unwrap();
+
+        let threads: std::vec::Vec<_> = (0..4).map(|i| {
+            let cloned = lock.clone();
+            let thread = std::thread::spawn(move || {
+                cloned.lock().unwrap().push(i);
+            });
+
+            // wait for this thread to take its ticket before starting the
+            // next one
+            while tickets_taken(&lock) != i + 2 {
+                std::thread::yield_now();
+            }
+
+            thread
+        }).collect();
+
+        drop(guard);
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(*lock.lock().unwrap(), [0, 1, 2, 3]);
+    }
+
+    #[cfg(feature = "std")]
+    fn tickets_taken<T>(lock: &TicketLock<T>) -> usize {
+        lock.next_ticket.load(::sync::atomic::Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,113 @@
+// run with: RUSTFLAGS="--cfg loom" cargo test --release --test loom
+
+#![cfg(loom)]
+
+extern crate loom;
+extern crate spinlock;
+
+use loom::cell::UnsafeCell;
+use loom::sync::Arc;
+
+use std::panic::AssertUnwindSafe;
+
+use spinlock::{ClhLock, McsLock, Spinlock};
+
+#[test]
+fn critical_sections_are_synchronized() {
+    loom::model(|| {
+        let spinlock = Arc::new(Spinlock::new(UnsafeCell::new(0usize)));
+
+        let threads: Vec<_> = (0..2).map(|_| {
+            let cloned = spinlock.clone();
+
+            loom::thread::spawn(move || {
+                let guard = cloned.lock().unwrap();
+
+                guard.with_mut(|data| unsafe { *data += 1 });
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let guard = spinlock.lock().unwrap();
+
+        assert_eq!(guard.with(|data| unsafe { *data }), 2);
+    });
+}
+
+#[test]
+fn poison_is_visible_to_next_owner() {
+    loom::model(|| {
+        let spinlock = Arc::new(Spinlock::new(()));
+        let cloned = spinlock.clone();
+
+        let thread = loom::thread::spawn(move || {
+            // loom can't model a thread that exits by unwinding
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                let _guard = cloned.lock().unwrap();
+
+                panic!("poisoning the lock");
+            }));
+
+            assert!(result.is_err());
+        });
+
+        thread.join().unwrap();
+        assert!(spinlock.lock().is_err());
+    });
+}
+
+#[test]
+fn mcs_critical_sections_are_synchronized() {
+    loom::model(|| {
+        let lock = Arc::new(McsLock::new(UnsafeCell::new(0usize)));
+
+        let threads: Vec<_> = (0..2).map(|_| {
+            let cloned = lock.clone();
+
+            loom::thread::spawn(move || {
+                let guard = cloned.lock().unwrap();
+
+                guard.with_mut(|data| unsafe { *data += 1 });
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let guard = lock.lock().unwrap();
+
+        assert_eq!(guard.with(|data| unsafe { *data }), 2);
+    });
+}
+
+#[test]
+fn clh_critical_sections_are_synchronized() {
+    loom::model(|| {
+        let lock = Arc::new(ClhLock::new(UnsafeCell::new(0usize)));
+
+        let threads: Vec<_> = (0..2).map(|_| {
+            let cloned = lock.clone();
+
+            loom::thread::spawn(move || {
+                let guard = cloned.lock().unwrap();
+
+                guard.with_mut(|data| unsafe { *data += 1 });
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let guard = match lock.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => panic!("lock should be idle"),
+        };
+
+        assert_eq!(guard.with(|data| unsafe { *data }), 2);
+    });
+}
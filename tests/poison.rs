@@ -0,0 +1,227 @@
+// runs the same poisoning scenarios against std's Mutex and Spinlock, which
+// should agree on every one of them
+
+#![cfg(feature = "std")]
+
+extern crate spinlock;
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use spinlock::Spinlock;
+
+trait Lock: Send + Sync + 'static {
+    fn new() -> Self;
+
+    fn lock_and<F: FnOnce()>(&self, f: F);
+
+    fn is_poisoned(&self) -> bool;
+
+    fn clear_poison(&self);
+}
+
+impl Lock for Mutex<()> {
+    fn new() -> Mutex<()> {
+        Mutex::new(())
+    }
+
+    fn lock_and<F: FnOnce()>(&self, f: F) {
+        let _guard = self.lock().unwrap_or_else(PoisonError::into_inner);
+
+        f();
+    }
+
+    fn is_poisoned(&self) -> bool {
+        Mutex::is_poisoned(self)
+    }
+
+    fn clear_poison(&self) {
+        Mutex::clear_poison(self)
+    }
+}
+
+impl Lock for Spinlock<()> {
+    fn new() -> Spinlock<()> {
+        Spinlock::new(())
+    }
+
+    fn lock_and<F: FnOnce()>(&self, f: F) {
+        let _guard = self.lock().unwrap_or_else(PoisonError::into_inner);
+
+        f();
+    }
+
+    fn is_poisoned(&self) -> bool {
+        Spinlock::is_poisoned(self)
+    }
+
+    fn clear_poison(&self) {
+        Spinlock::clear_poison(self)
+    }
+}
+
+struct LocksOnDrop<'a, L: Lock + 'a>(&'a L);
+
+impl<'a, L: Lock> Drop for LocksOnDrop<'a, L> {
+    fn drop(&mut self) {
+        self.0.lock_and(|| ());
+    }
+}
+
+fn panic_while_held<L: Lock>() -> bool {
+    let lock = L::new();
+    let _ = catch_unwind(AssertUnwindSafe(|| lock.lock_and(|| panic!())));
+
+    lock.is_poisoned()
+}
+
+fn panic_after_release<L: Lock>() -> bool {
+    let lock = L::new();
+
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        lock.lock_and(|| ());
+        panic!();
+    }));
+
+    lock.is_poisoned()
+}
+
+fn locked_while_unwinding<L: Lock>() -> bool {
+    let lock = L::new();
+
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        let _locks_on_drop = LocksOnDrop(&lock);
+        panic!();
+    }));
+
+    lock.is_poisoned()
+}
+
+fn panic_on_another_thread<L: Lock>() -> bool {
+    let lock = Arc::new(L::new());
+    let cloned = lock.clone();
+
+    let result = std::thread::spawn(move || cloned.lock_and(|| panic!())).join();
+    assert!(result.is_err());
+
+    lock.is_poisoned()
+}
+
+fn cleared_after_panic<L: Lock>() -> bool {
+    let lock = L::new();
+    let _ = catch_unwind(AssertUnwindSafe(|| lock.lock_and(|| panic!())));
+    lock.clear_poison();
+
+    lock.is_poisoned()
+}
+
+fn outcomes<L: Lock>() -> Vec<(&'static str, bool)> {
+    vec![
+        ("panic_while_held", panic_while_held::<L>()),
+        ("panic_after_release", panic_after_release::<L>()),
+        ("locked_while_unwinding", locked_while_unwinding::<L>()),
+        ("panic_on_another_thread", panic_on_another_thread::<L>()),
+        ("cleared_after_panic", cleared_after_panic::<L>()),
+    ]
+}
+
+#[test]
+fn spinlock_poisons_like_std() {
+    assert_eq!(outcomes::<Spinlock<()>>(), outcomes::<Mutex<()>>());
+}
+
+#[cfg(feature = "poison-info")]
+#[test]
+fn poison_info_describes_the_panic() {
+    let lock = Arc::new(Spinlock::new(()));
+    assert!(lock.poison_info().is_none());
+
+    let cloned = lock.clone();
+    let thread = std::thread::Builder::new()
+        .name("poisoner".to_string())
+        .spawn(move || {
+            let _guard = cloned.lock().unwrap();
+            panic!("poisoned by {}", "poisoner");
+        })
+        .unwrap();
+    let thread_id = thread.thread().id();
+    assert!(thread.join().is_err());
+
+    let info = lock.poison_info().unwrap();
+    assert_eq!(info.thread_id(), thread_id);
+    assert_eq!(info.thread_name(), Some("poisoner"));
+    assert!(info.time() <= std::time::SystemTime::now());
+    assert_eq!(info.message(), Some("poisoned by poisoner"));
+    assert!(info.location().unwrap().starts_with(file!()));
+
+    // only the first panic is kept
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        let _guard = lock.lock();
+        panic!("again");
+    }));
+
+    assert_eq!(lock.poison_info().unwrap().thread_id(), thread_id);
+
+    lock.clear_poison();
+    assert!(lock.poison_info().is_none());
+
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        let _guard = lock.lock().unwrap();
+        panic!("again");
+    }));
+
+    let info = lock.poison_info().unwrap();
+    assert_eq!(info.message(), Some("again"));
+    assert_eq!(info.thread_id(), std::thread::current().id());
+}
+
+#[cfg(feature = "poison-info")]
+#[test]
+fn errors_carry_poison_info() {
+    use spinlock::TryLockErrorWithInfo;
+
+    let lock = Spinlock::new(1);
+
+    let guard = lock.lock_with_info().unwrap();
+
+    match lock.try_lock_with_info() {
+        Err(TryLockErrorWithInfo::WouldBlock) => (),
+        _ => panic!("expected WouldBlock"),
+    }
+
+    drop(guard);
+
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        let mut guard = lock.lock().unwrap();
+        *guard += 1;
+        panic!("poisoned with {}", *guard);
+    }));
+
+    let err = lock.lock_with_info().unwrap_err();
+    assert_eq!(err.info().message(), Some("poisoned with 2"));
+    assert!(err.to_string().contains("poisoned with 2"));
+
+    // the error keeps its info even once the poison is cleared
+    lock.clear_poison();
+    assert!(lock.poison_info().is_none());
+    assert_eq!(err.info().thread_id(), std::thread::current().id());
+    assert_eq!(*PoisonError::from(err).into_inner(), 2);
+
+    assert!(lock.try_lock_with_info().is_ok());
+
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        let _guard = lock.lock().unwrap();
+        panic!("poisoned again");
+    }));
+
+    match lock.try_lock_with_info() {
+        Err(TryLockErrorWithInfo::Poisoned(err)) => {
+            assert_eq!(err.info().message(), Some("poisoned again"));
+        },
+        _ => panic!("expected Poisoned"),
+    }
+
+    let err = lock.into_inner_with_info().unwrap_err();
+    assert_eq!(err.info().message(), Some("poisoned again"));
+    assert_eq!(err.into_inner(), 2);
+}